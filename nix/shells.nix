@@ -21,6 +21,8 @@
           pkgs.just
           pkgs.pnpm
           pkgs.nodejs
+          # sshd for the native ssh backend tests
+          pkgs.openssh
         ];
 
         PROTOC = lib.getExe pkgs.protobuf;
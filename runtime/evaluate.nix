@@ -83,6 +83,16 @@ rec {
   inherit nodes;
 
   topLevels = builtins.mapAttrs (name: _: getTopLevel name) nodes;
+  # only forces the given nodes, so one node failing to evaluate does not
+  # affect a deployment that does not select it
+  topLevelsOf =
+    names:
+    builtins.listToAttrs (
+      map (name: {
+        inherit name;
+        value = getTopLevel name;
+      }) names
+    );
   inspect = {
     _schema = 1;
 
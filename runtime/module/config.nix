@@ -7,6 +7,36 @@
   config,
   ...
 }:
+let
+  # Attach type to internally tag serde enum
+  tagSource =
+    source:
+    if builtins.isAttrs source then
+      if source ? age then
+        {
+          t = "Age";
+          c = source.age;
+        }
+      else if source ? sops then
+        {
+          t = "Sops";
+          c = source.sops;
+        }
+      else
+        {
+          t = "Template";
+          c = source.template // {
+            values = builtins.mapAttrs (_: tagSource) source.template.values;
+          };
+        }
+    else
+      {
+        t = builtins.replaceStrings [ "path" "string" "list" ] [ "Path" "String" "Command" ] (
+          builtins.typeOf source
+        );
+        c = source;
+      };
+in
 {
   config = {
     systemd = {
@@ -63,13 +93,7 @@
         _: value:
         value
         // {
-          source = {
-            # Attach type to internally tag serde enum
-            t = builtins.replaceStrings [ "path" "string" "list" ] [ "Path" "String" "Command" ] (
-              builtins.typeOf value.source
-            );
-            c = value.source;
-          };
+          source = tagSource value.source;
         }
       ) config.deployment.keys;
 
@@ -8,6 +8,54 @@
 }:
 let
   inherit (lib) types;
+
+  identities = lib.mkOption {
+    # strings, so identities are never copied to the nix store
+    type = types.listOf types.str;
+    default = [ ];
+    description = "Absolute paths to age identity files. Defaults to `$SOPS_AGE_KEY_FILE`, `$SOPS_AGE_KEY` and `~/.config/sops/age/keys.txt`.";
+  };
+
+  encryptedSources = {
+    age = lib.mkOption {
+      type = types.submodule {
+        options = {
+          file = lib.mkOption {
+            type = types.path;
+            description = "File encrypted with age.";
+          };
+          inherit identities;
+        };
+      };
+      description = "Decrypt a file encrypted with age.";
+    };
+    sops = lib.mkOption {
+      type = types.submodule {
+        options = {
+          file = lib.mkOption {
+            type = types.path;
+            description = "JSON or YAML file encrypted with sops for age, named `*.json`, `*.yaml` or `*.yml`.";
+          };
+          key = lib.mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "db.password";
+            description = "`.` separated path of the value to use. Uses the whole decrypted file if null.";
+          };
+          inherit identities;
+        };
+      };
+      description = "Decrypt a file encrypted with sops.";
+    };
+  };
+
+  # any key source except templates
+  valueSource = types.oneOf [
+    types.str
+    types.path
+    (types.listOf types.str)
+    (types.attrTag encryptedSources)
+  ];
 in
 {
   imports =
@@ -18,6 +66,7 @@ in
       (mkAliasOptionModule [ "deployment" "targetHost" ] [ "deployment" "target" "hosts" ])
       (mkAliasOptionModule [ "deployment" "targetUser" ] [ "deployment" "target" "user" ])
       (mkAliasOptionModule [ "deployment" "targetPort" ] [ "deployment" "target" "port" ])
+      (mkAliasOptionModule [ "deployment" "sshOptions" ] [ "deployment" "target" "sshOptions" ])
     ];
 
   options.deployment = {
@@ -44,6 +93,19 @@ in
             default = 22;
             description = "SSH port to use.";
           };
+          sshOptions = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Extra arguments passed to every `ssh` invocation for this node, including `NIX_SSHOPTS` when
+            copying closures. They come before wire's own `-o` options, so they override them. Arguments must not contain
+            spaces.";
+            example = [
+              "-o"
+              "ProxyJump=bastion.example.com"
+              "-i"
+              "/home/user/.ssh/deploy"
+            ];
+          };
         };
       };
       description = "Describes the target for this node";
@@ -56,6 +118,16 @@ in
       description = "Whether to build the system on the target host or not.";
     };
 
+    buildOn = lib.mkOption {
+      type = types.nullOr (types.either types.str (types.listOf types.str));
+      default = null;
+      description = "Where to build the system, instead of this machine or the target host.
+      Either the name of another node in the hive, which the derivation is pushed to and the
+      closure is copied from straight to the target, or a list of machines passed to
+      `nix build --builders`. Takes precedence over `buildOnTarget`.";
+      example = "aarch64-builder";
+    };
+
     allowLocalDeployment = lib.mkOption {
       type = types.bool;
       default = true;
@@ -73,6 +145,17 @@ in
       ];
     };
 
+    dependsOn = lib.mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      description = "Names of nodes that must be deployed before this node. If one of them fails, this node is
+      skipped. Only applies to nodes selected in the same `wire apply`.";
+      example = [
+        "db-primary"
+        "bastion"
+      ];
+    };
+
     privilegeEscalationCommand = lib.mkOption {
       type = types.listOf types.str;
       description = "Command to elevate.";
@@ -82,16 +165,76 @@ in
       ];
     };
 
-    replaceUnknownProfiles = lib.mkOption {
+    autoRollback = lib.mkOption {
       type = types.bool;
-      description = "No-op, colmena compatibility";
-      default = true;
+      default = false;
+      description = "Whether to switch back to the previously running system if activation or the node's health
+      checks fail.";
     };
 
-    sshOptions = lib.mkOption {
-      type = types.listOf types.str;
-      description = "No-op, colmena compatibility";
-      default = [ ];
+    magicRollback = lib.mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to arm a timer on the node before activation that switches back to the previous system
+      unless wire reconnects over a fresh SSH session and confirms the activation. Protects against configurations
+      that break SSH or networking, even if activation never returns. A failed activation of a reachable node is
+      rolled back straight away. Only applies to the `switch` and `test` goals.";
+    };
+
+    confirmTimeout = lib.mkOption {
+      type = types.ints.positive;
+      default = 300;
+      description = "Seconds before the node rolls back when `deployment.magicRollback` is enabled. The timer starts
+      before `switch-to-configuration` runs, so this must cover the activation as well as reconnecting to the node.";
+    };
+
+    healthChecks = lib.mkOption {
+      type = types.submodule {
+        options = {
+          units = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "systemd units that must be active after activation.";
+            example = [ "nginx.service" ];
+          };
+          http = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "URLs that must respond with a successful status code after activation. These are probed
+            from the machine running wire with `curl`.";
+            example = [ "https://example.com/health" ];
+          };
+          commands = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Shell commands ran on the node as the SSH user after activation, each with `sh -c`. A
+            non-zero exit status fails the health check.";
+            example = [ "test -f /var/lib/app/ready" ];
+          };
+          attempts = lib.mkOption {
+            type = types.ints.positive;
+            default = 3;
+            description = "How many times to run the health checks before considering the node unhealthy.";
+          };
+          interval = lib.mkOption {
+            type = types.ints.unsigned;
+            default = 5;
+            description = "Seconds to wait between health check attempts.";
+          };
+        };
+      };
+      default = { };
+      description = "Health checks ran after `switch` or `test` activation. If they fail, the node is rolled back
+      when `deployment.autoRollback` is enabled.";
+    };
+
+    replaceUnknownProfiles = lib.mkOption {
+      type = types.bool;
+      description = "Whether to replace the node's system profile when it was not set by wire, for example when the
+      node was last switched with `nixos-rebuild`. Can be overridden with `--replace-unknown-profiles`. wire records
+      the profiles it sets in `/var/lib/wire/system-profile`, which older versions of wire did not write, so nodes last
+      deployed by them are unknown as well. Deploy those once with `--replace-unknown-profiles` before disabling this.";
+      default = true;
     };
 
     _keys = lib.mkOption {
@@ -169,8 +312,33 @@ in
                   types.str
                   types.path
                   (types.listOf types.str)
+                  (types.attrTag (
+                    encryptedSources
+                    // {
+                      template = lib.mkOption {
+                        type = types.submodule {
+                          options = {
+                            text = lib.mkOption {
+                              type = types.str;
+                              example = ''
+                                host={{ node.host }}
+                                token={{ values.token }}
+                              '';
+                              description = "Text of the key. `{{ node.name }}`, `{{ node.host }}`, `{{ node.tags }}` and `{{ values.<name> }}` are replaced before the key is uploaded.";
+                            };
+                            values = lib.mkOption {
+                              type = types.attrsOf valueSource;
+                              default = { };
+                              description = "Sources of the `{{ values.<name> }}` placeholders. A trailing newline is removed from each value.";
+                            };
+                          };
+                        };
+                        description = "Render a template with the node's name, target host, tags and values read from other sources.";
+                      };
+                    }
+                  ))
                 ];
-                description = "Source of the key. Either a path to a file, a literal string, or a command to generate the key.";
+                description = "Source of the key. Either a path to a file, a literal string, a command to generate the key, an `age` or `sops` encrypted file, or a `template`.";
               };
               uploadAt = lib.mkOption {
                 type = types.enum [
@@ -185,6 +353,28 @@ in
                 default = { };
                 description = "Key-Value environment variables to use when creating the key if the key source is a command.";
               };
+              restartUnits = lib.mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                example = [ "nginx.service" ];
+                description = "Systemd units to restart after the content of the key changed. Units of `pre-activation` keys are restarted after activation, and units of keys with `elevate = false` are the SSH user's units.";
+              };
+              reloadUnits = lib.mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                example = [ "nginx.service" ];
+                description = "Systemd units to reload after the content of the key changed. Units that are not running are started instead. Units of `pre-activation` keys are reloaded after activation, and units of keys with `elevate = false` are the SSH user's units.";
+              };
+              elevate = lib.mkOption {
+                type = types.bool;
+                default = true;
+                description = "Whether to write the key with `deployment.privilegeEscalationCommand`. When disabled, the key is written by the SSH user, who must own the key and be able to write to `destDir`.";
+              };
+              cache = lib.mkOption {
+                type = types.nullOr types.bool;
+                default = null;
+                description = "Whether to run the key's command, or decrypt its `age` or `sops` file, once per run and share the result with every node that has the same source and `environment`. When unset, `age` and `sops` files are shared, but commands are run for each node, as they may generate a different key for each node.";
+              };
             };
           }
         )
@@ -0,0 +1,25 @@
+# SPDX-License-Identifier: AGPL-3.0-or-later
+# Copyright 2024-2025 wire Contributors
+
+let
+  inherit (import ../../..) makeHive;
+in
+makeHive {
+  meta = {
+    nixpkgs = <nixpkgs>;
+  };
+
+  node-a = {
+    nixpkgs.hostPlatform = "x86_64-linux";
+  };
+
+  node-b = {
+    nixpkgs.hostPlatform = "x86_64-linux";
+  };
+
+  # never selected, would fail the batch if it was evaluated
+  node-c = {
+    nixpkgs.hostPlatform = "x86_64-linux";
+    boot.kernelPackages = builtins.abort "node-c should not be evaluated";
+  };
+}
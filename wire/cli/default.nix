@@ -52,7 +52,7 @@
             pkgs.makeWrapper
           ];
           postBuild = ''
-            wrapProgram $out/bin/wire ${agents}
+            wrapProgram $out/bin/wire ${agents} --set WIRE_RUNTIME ${../../runtime}
           '';
           meta.mainProgram = "wire";
         };
@@ -64,7 +64,8 @@
             pkgs.makeWrapper
           ];
           postBuild = ''
-            wrapProgram $out/bin/wire --set WIRE_KEY_AGENT_${cleanSystem system} ${self'.packages.agent}
+            wrapProgram $out/bin/wire --set WIRE_KEY_AGENT_${cleanSystem system} ${self'.packages.agent} \
+              --set WIRE_RUNTIME ${../../runtime}
           '';
           meta.mainProgram = "wire";
         };
@@ -1,20 +1,29 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
-use futures::{FutureExt, StreamExt};
+use clap::ValueEnum;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
 use itertools::{Either, Itertools};
-use lib::hive::node::{Context, GoalExecutor, Name, StepState, should_apply_locally};
+use lib::hive::node::{
+    BuildLocation, Context, GoalExecutor, Name, Node, StepState, should_apply_locally,
+};
+use lib::hive::steps::build::BatchedBuild;
+use lib::hive::steps::evaluate::{BatchedEvaluation, EvalCache};
 use lib::hive::{Hive, HiveLocation};
 use lib::status::STATUS;
 use lib::{SubCommandModifiers, errors::HiveLibError};
 use miette::{Diagnostic, IntoDiagnostic, Result};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Read, stderr};
 use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
-use tracing::{Span, error, info};
+use tracing::{Span, error, info, warn};
 
-use crate::cli::{ApplyArgs, ApplyTarget};
+use crate::cli::{ApplyArgs, ApplyTarget, Goal, NodeAmount};
+use crate::history::{self, Deployment, NodeRecord};
+use crate::output::{ErrorInfo, Event, NodeOutcome, NodeResult, emit, print_diff, print_key_drift};
 
 #[derive(Debug, Error, Diagnostic)]
 #[error("node {} failed to apply", .0)]
@@ -48,22 +57,214 @@ fn read_apply_targets_from_stdin() -> Result<(Vec<String>, Vec<Name>)> {
         }))
 }
 
-// #[instrument(skip_all, fields(goal = %args.goal, on = %args.on.iter().join(", ")))]
-pub async fn apply(
-    hive: &mut Hive,
-    location: HiveLocation,
-    args: ApplyArgs,
-    mut modifiers: SubCommandModifiers,
-) -> Result<()> {
-    let header_span = Span::current();
-    let location = Arc::new(location);
+/// The tag a node is batched under with `--batch-per-tag`.
+fn batch_tag(node: &Node, selected_tags: &HashSet<String>) -> Option<String> {
+    node.tags
+        .iter()
+        .filter(|tag| selected_tags.contains(*tag))
+        .min()
+        .or_else(|| node.tags.iter().min())
+        .cloned()
+}
 
-    // Respect user's --always-build-local arg
-    hive.force_always_local(args.always_build_local)?;
+/// Splits the selected nodes into batches that are deployed one after
+/// another.
+fn plan_batches<'a>(
+    nodes: Vec<(&'a Name, &'a mut Node)>,
+    size: Option<NodeAmount>,
+    per_tag: bool,
+    selected_tags: &HashSet<String>,
+) -> Vec<Vec<(&'a Name, &'a mut Node)>> {
+    let Some(size) = size else {
+        return vec![nodes];
+    };
 
-    let header_span_enter = header_span.enter();
+    let chunk = |group: Vec<(&'a Name, &'a mut Node)>| {
+        let chunk_size = size.of(group.len()).max(1);
+
+        group
+            .into_iter()
+            .chunks(chunk_size)
+            .into_iter()
+            .map(Iterator::collect::<Vec<_>>)
+            .collect::<Vec<_>>()
+    };
+
+    if !per_tag {
+        return chunk(nodes);
+    }
+
+    let mut groups: BTreeMap<Option<String>, Vec<_>> = BTreeMap::new();
+
+    for (name, node) in nodes {
+        groups
+            .entry(batch_tag(node, selected_tags))
+            .or_default()
+            .push((name, node));
+    }
+
+    let mut batches: Vec<Vec<_>> = Vec::new();
+
+    for group in groups.into_values() {
+        for (index, nodes) in chunk(group).into_iter().enumerate() {
+            if batches.len() <= index {
+                batches.push(Vec::new());
+            }
+
+            batches[index].extend(nodes);
+        }
+    }
+
+    batches
+}
+
+/// Moves nodes into later batches until each is deployed in a batch after
+/// all of its dependencies, so batches keep their size.
+fn order_batches<'a>(
+    batches: Vec<Vec<(&'a Name, &'a mut Node)>>,
+    dependencies: &HashMap<&Name, Vec<Name>>,
+) -> Vec<Vec<(&'a Name, &'a mut Node)>> {
+    let mut positions: HashMap<&Name, usize> = batches
+        .iter()
+        .enumerate()
+        .flat_map(|(index, batch)| batch.iter().map(move |(name, _)| (*name, index)))
+        .collect();
+
+    // terminates as the hive was checked for dependency cycles
+    loop {
+        let mut changed = false;
+
+        for (name, node_dependencies) in dependencies {
+            let after = node_dependencies
+                .iter()
+                .filter_map(|dependency| positions.get(dependency))
+                .max()
+                .map(|latest| latest + 1);
+
+            if let Some(after) = after
+                && after > positions[name]
+            {
+                positions.insert(*name, after);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut ordered: Vec<Vec<_>> = Vec::new();
+
+    for (name, node) in batches.into_iter().flatten() {
+        let position = positions[name];
+
+        if ordered.len() <= position {
+            ordered.resize_with(position + 1, Vec::new);
+        }
+
+        ordered[position].push((name, node));
+    }
+
+    ordered.retain(|batch| !batch.is_empty());
+    ordered
+}
+
+enum DependencyState<'a> {
+    Satisfied,
+    Waiting,
+    Failed(&'a Name),
+}
 
-    let (tags, names) = args.on.iter().fold(
+/// Dependencies that were not selected for this apply are always satisfied.
+fn dependency_state<'a>(
+    node_dependencies: &'a [Name],
+    selected: &HashMap<&Name, Vec<Name>>,
+    outcomes: &HashMap<&Name, bool>,
+) -> DependencyState<'a> {
+    let mut state = DependencyState::Satisfied;
+
+    for dependency in node_dependencies {
+        match outcomes.get(dependency) {
+            Some(false) => return DependencyState::Failed(dependency),
+            None if selected.contains_key(dependency) => state = DependencyState::Waiting,
+            Some(true) | None => {}
+        }
+    }
+
+    state
+}
+
+/// What happened to each node, for the summary and the deployment history.
+#[derive(Default)]
+struct Outcomes<'a> {
+    results: Vec<(&'a Name, Result<(), HiveLibError>)>,
+    succeeded: HashMap<&'a Name, bool>,
+    reports: Vec<NodeResult<'a>>,
+    records: Vec<NodeRecord>,
+}
+
+impl<'a> Outcomes<'a> {
+    /// Emits the outcome of a node and keeps it for the summary.
+    fn report(&mut self, result: NodeResult<'a>) {
+        emit(&Event::NodeFinished(result.clone()));
+        self.reports.push(result);
+    }
+
+    fn skip(&mut self, name: &'a Name) {
+        STATUS.lock().mark_node_skipped(name);
+        self.succeeded.insert(name, false);
+        self.records.push(NodeRecord {
+            name: name.to_string(),
+            status: NodeOutcome::Skipped,
+            toplevel: None,
+            duration_ms: None,
+            error: None,
+            steps: Vec::new(),
+        });
+        self.report(NodeResult {
+            node: &name.0,
+            status: NodeOutcome::Skipped,
+            duration_ms: None,
+            error: None,
+        });
+    }
+
+    fn finish(&mut self, name: &'a Name, result: Result<(), HiveLibError>, record: NodeRecord) {
+        self.report(NodeResult {
+            node: &name.0,
+            status: record.status,
+            duration_ms: record.duration_ms,
+            error: result.as_ref().err().map(ErrorInfo::from),
+        });
+        self.records.push(record);
+        self.succeeded.insert(name, result.is_ok());
+        self.results.push((name, result));
+    }
+
+    fn failed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+
+    fn count(&self, outcome: NodeOutcome) -> usize {
+        self.reports
+            .iter()
+            .filter(|report| report.status == outcome)
+            .count()
+    }
+}
+
+/// Selects the nodes matching `--on`, in a stable order so batches are the
+/// same between runs, and reports them. Returns them with the selected tags.
+fn select_nodes<'a>(
+    hive: &'a mut Hive,
+    on: &[ApplyTarget],
+    modifiers: &mut SubCommandModifiers,
+) -> (Vec<(&'a Name, &'a mut Node)>, HashSet<String>) {
+    let (tags, names) = on.iter().fold(
         (HashSet::new(), HashSet::new()),
         |(mut tags, mut names), target| {
             match target {
@@ -86,16 +287,16 @@ pub async fn apply(
         },
     );
 
-    let selected_nodes: Vec<_> = hive
+    let mut selected_nodes: Vec<_> = hive
         .nodes
         .iter_mut()
         .filter(|(name, node)| {
-            args.on.is_empty()
-                || names.contains(name)
-                || node.tags.iter().any(|tag| tags.contains(tag))
+            on.is_empty() || names.contains(name) || node.tags.iter().any(|tag| tags.contains(tag))
         })
         .collect();
 
+    selected_nodes.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+
     STATUS.lock().add_many(
         &selected_nodes
             .iter()
@@ -103,40 +304,322 @@ pub async fn apply(
             .collect::<Vec<_>>(),
     );
 
-    let mut set = selected_nodes
-        .into_iter()
-        .map(|(name, node)| {
-            info!("Resolved {:?} to include {}", args.on, name);
+    if selected_nodes.is_empty() {
+        error!("There are no nodes selected for deployment");
+    }
+
+    for (name, _) in &selected_nodes {
+        emit(&Event::NodeSelected { node: &name.0 });
+    }
+
+    (selected_nodes, tags)
+}
+
+/// Builds the locally built nodes of a single batch together, so no node
+/// waits on the build of a later batch.
+fn batched_build(
+    batch: &[(&Name, &mut Node)],
+    batched_evaluation: Option<&Arc<BatchedEvaluation>>,
+    modifiers: SubCommandModifiers,
+) -> Option<Arc<BatchedBuild>> {
+    let batched_evaluation = batched_evaluation?;
+    let names = batch
+        .iter()
+        .filter(|(name, node)| {
+            node.build_location(should_apply_locally(node.allow_local_deployment, &name.0))
+                == BuildLocation::Local
+        })
+        .map(|(name, _)| (*name).clone())
+        .collect::<Vec<_>>();
+
+    // a single node gains nothing from a batch
+    (names.len() > 1).then(|| {
+        Arc::new(BatchedBuild::new(
+            names,
+            batched_evaluation.clone(),
+            modifiers,
+        ))
+    })
+}
+
+/// Runs the goal on a node, timing it for the deployment history.
+async fn deploy_node<'a>(
+    name: &'a Name,
+    context: Context<'a>,
+) -> (&'a Name, Result<(), HiveLibError>, NodeRecord) {
+    let started = Instant::now();
+    let mut executor = GoalExecutor::new(context);
+    let result = executor.execute().await;
 
-            let should_apply_locally = should_apply_locally(node.allow_local_deployment, &name.0);
+    if let Some(diff) = executor.diff() {
+        print_diff(&name.0, diff);
+    }
+
+    if let Some(keys) = executor.key_drift() {
+        print_key_drift(&name.0, keys);
+    }
 
-            let context = Context {
-                node,
-                name,
-                goal: args.goal.clone().try_into().unwrap(),
-                state: StepState::default(),
-                no_keys: args.no_keys,
-                hive_location: location.clone(),
+    let record = NodeRecord {
+        name: name.to_string(),
+        status: if result.is_ok() {
+            NodeOutcome::Succeeded
+        } else {
+            NodeOutcome::Failed
+        },
+        toplevel: executor.built_system().map(str::to_string),
+        duration_ms: Some(u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX)),
+        error: result.as_ref().err().map(ToString::to_string),
+        steps: executor.timings().to_vec(),
+    };
+
+    (name, result, record)
+}
+
+/// Everything the nodes of an apply share.
+struct Shared<'s> {
+    args: &'s ApplyArgs,
+    location: Arc<HiveLocation>,
+    modifiers: SubCommandModifiers,
+    batched_evaluation: Option<Arc<BatchedEvaluation>>,
+    eval_cache: Option<Arc<EvalCache>>,
+}
+
+impl<'s> Shared<'s> {
+    fn new(
+        args: &'s ApplyArgs,
+        location: Arc<HiveLocation>,
+        modifiers: SubCommandModifiers,
+        names: Vec<Name>,
+    ) -> Result<Self, HiveLibError> {
+        let eval_cache = if args.no_eval_cache {
+            None
+        } else {
+            EvalCache::new(location.clone(), modifiers)?.map(Arc::new)
+        };
+        // a single node gains nothing from a batch
+        let batched_evaluation = (names.len() > 1).then(|| {
+            Arc::new(BatchedEvaluation::new(
+                names,
+                location.clone(),
                 modifiers,
-                reboot: args.reboot,
-                should_apply_locally,
-                handle_unreachable: args.handle_unreachable.clone().into(),
-            };
-
-            GoalExecutor::new(context)
-                .execute()
-                .map(move |result| (name, result))
+                eval_cache.clone(),
+            ))
+        });
+
+        Ok(Self {
+            args,
+            location,
+            modifiers,
+            batched_evaluation,
+            eval_cache,
         })
-        .peekable();
+    }
 
-    if set.peek().is_none() {
-        error!("There are no nodes selected for deployment");
+    fn context<'a>(
+        &self,
+        name: &'a Name,
+        node: &'a mut Node,
+        batched_build: Option<Arc<BatchedBuild>>,
+    ) -> Context<'a> {
+        let should_apply_locally = should_apply_locally(node.allow_local_deployment, &name.0);
+
+        Context {
+            node,
+            name,
+            goal: self.args.goal.clone().try_into().unwrap(),
+            state: StepState::default(),
+            no_keys: self.args.no_keys,
+            hive_location: self.location.clone(),
+            modifiers: self.modifiers,
+            reboot: self.args.reboot,
+            should_apply_locally,
+            handle_unreachable: self.args.handle_unreachable.clone().into(),
+            replace_unknown_profiles: self.args.replace_unknown_profiles,
+            batched_evaluation: self.batched_evaluation.clone(),
+            eval_cache: self.eval_cache.clone(),
+            batched_build,
+        }
+    }
+}
+
+/// Deploys the `number`th of `batches` batches, running up to `--parallel`
+/// nodes at once. Nodes are started once their dependencies succeeded, and
+/// skipped if one failed.
+async fn deploy_batch<'a>(
+    shared: &Shared<'_>,
+    (number, batches): (usize, usize),
+    batch: Vec<(&'a Name, &'a mut Node)>,
+    dependencies: &HashMap<&Name, Vec<Name>>,
+    outcomes: &mut Outcomes<'a>,
+) {
+    emit(&Event::BatchStarted {
+        batch: number,
+        batches,
+        nodes: batch.iter().map(|(name, _)| &*name.0).collect(),
+    });
+
+    if batches > 1 {
+        info!(
+            "Deploying batch {number}/{batches}: {}",
+            batch.iter().map(|(name, _)| name).join(", ")
+        );
+    }
+
+    let batched_build = shared
+        .args
+        .build_together
+        .then(|| batched_build(&batch, shared.batched_evaluation.as_ref(), shared.modifiers))
+        .flatten();
+    let mut pending = batch;
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        let mut position = 0;
+
+        while position < pending.len() {
+            let name = pending[position].0;
+
+            match dependency_state(&dependencies[name], dependencies, &outcomes.succeeded) {
+                DependencyState::Failed(dependency) => {
+                    pending.remove(position);
+
+                    warn!("Skipping {name}, its dependency {dependency} did not succeed");
+                    outcomes.skip(name);
+
+                    // nodes already passed over may depend on this one
+                    position = 0;
+                }
+                DependencyState::Satisfied if running.len() < shared.args.parallel => {
+                    let (name, node) = pending.remove(position);
+
+                    info!("Resolved {:?} to include {}", shared.args.on, name);
+
+                    running.push(deploy_node(
+                        name,
+                        shared.context(name, node, batched_build.clone()),
+                    ));
+                }
+                DependencyState::Satisfied | DependencyState::Waiting => position += 1,
+            }
+        }
+
+        let Some((name, result, record)) = running.next().await else {
+            break;
+        };
+
+        outcomes.finish(name, result, record);
+    }
+
+    // nodes waiting on a dependency that is never deployed in this batch
+    for (name, _) in pending {
+        warn!("Skipping {name}, its dependencies were not deployed before it");
+        outcomes.skip(name);
+    }
+}
+
+/// Records the deployment in the history. `wire diff` and `wire keys verify`
+/// do not deploy anything.
+fn save_history(goal: &Goal, location: &HiveLocation, started_at: u64, records: Vec<NodeRecord>) {
+    if matches!(goal, Goal::Diff | Goal::VerifyKeys) || records.is_empty() {
+        return;
+    }
+
+    let deployment = Deployment {
+        started_at,
+        finished_at: history::now(),
+        user: history::user(),
+        location: history::location(location),
+        revision: history::revision(location),
+        goal: goal
+            .to_possible_value()
+            .map_or_else(|| goal.to_string(), |value| value.get_name().to_string()),
+        nodes: records,
+    };
+
+    if let Err(err) = deployment.save() {
+        warn!("Failed to record the deployment history: {err}");
+    }
+}
+
+// #[instrument(skip_all, fields(goal = %args.goal, on = %args.on.iter().join(", ")))]
+pub async fn apply(
+    hive: &mut Hive,
+    location: HiveLocation,
+    args: ApplyArgs,
+    mut modifiers: SubCommandModifiers,
+) -> Result<()> {
+    let header_span = Span::current();
+    let started_at = history::now();
+    let location = Arc::new(location);
+
+    // Respect user's --always-build-local arg
+    hive.force_always_local(args.always_build_local.clone())?;
+
+    let header_span_enter = header_span.enter();
+
+    let (selected_nodes, tags) = select_nodes(hive, &args.on, &mut modifiers);
+
+    let total = selected_nodes.len();
+    let shared = Shared::new(
+        &args,
+        location.clone(),
+        modifiers,
+        selected_nodes
+            .iter()
+            .map(|(name, _)| (*name).clone())
+            .collect(),
+    )?;
+    let failure_budget = args.max_failures.map(|amount| amount.of(total));
+    let dependencies: HashMap<&Name, Vec<Name>> = selected_nodes
+        .iter()
+        .map(|(name, node)| (*name, node.depends_on.iter().cloned().collect()))
+        .collect();
+    let batches = plan_batches(selected_nodes, args.batch_size, args.batch_per_tag, &tags);
+    // without batches, nodes wait for their dependencies within the one batch
+    let batches = if args.batch_size.is_some() {
+        order_batches(batches, &dependencies)
+    } else {
+        batches
+    };
+    let num_batches = batches.len();
+
+    let mut outcomes = Outcomes::default();
+    let mut skipped = Vec::new();
+
+    for (index, batch) in batches.into_iter().enumerate() {
+        if let Some(budget) = failure_budget
+            && outcomes.failed() > budget
+        {
+            for (name, _) in batch {
+                outcomes.skip(name);
+                skipped.push(name);
+            }
+
+            continue;
+        }
+
+        deploy_batch(
+            &shared,
+            (index + 1, num_batches),
+            batch,
+            &dependencies,
+            &mut outcomes,
+        )
+        .await;
     }
 
-    let futures = futures::stream::iter(set).buffer_unordered(args.parallel);
-    let result = futures.collect::<Vec<_>>().await;
+    emit(&Event::Summary {
+        succeeded: outcomes.count(NodeOutcome::Succeeded),
+        failed: outcomes.count(NodeOutcome::Failed),
+        skipped: outcomes.count(NodeOutcome::Skipped),
+        nodes: outcomes.reports.clone(),
+    });
+
+    save_history(&args.goal, &location, started_at, outcomes.records);
+
     let (successful, errors): (Vec<_>, Vec<_>) =
-        result
+        outcomes
+            .results
             .into_iter()
             .partition_map(|(name, result)| match result {
                 Ok(..) => Either::Left(name),
@@ -151,6 +634,15 @@ pub async fn apply(
         );
     }
 
+    if !skipped.is_empty() {
+        error!(
+            "More than {} node(s) failed, skipped the remaining {} node(s): {}",
+            failure_budget.unwrap_or_default(),
+            skipped.len(),
+            skipped.iter().join(", ")
+        );
+    }
+
     std::mem::drop(header_span_enter);
     std::mem::drop(header_span);
 
@@ -169,3 +661,163 @@ pub async fn apply(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(batches: &[Vec<(&Name, &mut Node)>]) -> Vec<Vec<String>> {
+        batches
+            .iter()
+            .map(|batch| batch.iter().map(|(name, _)| name.to_string()).collect())
+            .collect()
+    }
+
+    /// Nodes named `node-{index}`, as the hive evaluates them.
+    fn nodes(tags: &[&[&str]]) -> Vec<(Name, Node)> {
+        tags.iter()
+            .enumerate()
+            .map(|(index, tags)| {
+                let node = serde_json::json!({
+                    "target": {
+                        "hosts": ["localhost"],
+                        "user": "root",
+                        "port": 22,
+                        "sshOptions": [],
+                    },
+                    "buildOnTarget": false,
+                    "allowLocalDeployment": true,
+                    "tags": tags,
+                    "_keys": [],
+                    "_hostPlatform": "x86_64-linux",
+                    "privilegeEscalationCommand": ["sudo", "--"],
+                    "replaceUnknownProfiles": true,
+                    "autoRollback": false,
+                    "magicRollback": false,
+                    "confirmTimeout": 60,
+                    "healthChecks": {
+                        "units": [],
+                        "http": [],
+                        "commands": [],
+                        "attempts": 3,
+                        "interval": 5,
+                    },
+                });
+
+                (
+                    Name(format!("node-{index}").into()),
+                    serde_json::from_value(node).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn selected(hive: &mut [(Name, Node)]) -> Vec<(&Name, &mut Node)> {
+        hive.iter_mut().map(|(name, node)| (&*name, node)).collect()
+    }
+
+    #[test]
+    fn test_plan_batches() {
+        let mut hive = nodes(&[&[], &[], &[], &[], &[]]);
+
+        assert_eq!(
+            names(&plan_batches(
+                selected(&mut hive),
+                None,
+                false,
+                &HashSet::new()
+            )),
+            vec![vec!["node-0", "node-1", "node-2", "node-3", "node-4"]]
+        );
+        assert_eq!(
+            names(&plan_batches(
+                selected(&mut hive),
+                Some(NodeAmount::Count(2)),
+                false,
+                &HashSet::new()
+            )),
+            vec![
+                vec!["node-0", "node-1"],
+                vec!["node-2", "node-3"],
+                vec!["node-4"]
+            ]
+        );
+        assert_eq!(
+            names(&plan_batches(
+                selected(&mut hive),
+                Some(NodeAmount::Percent(40)),
+                false,
+                &HashSet::new()
+            )),
+            vec![
+                vec!["node-0", "node-1"],
+                vec!["node-2", "node-3"],
+                vec!["node-4"]
+            ]
+        );
+        // a percentage rounding down to zero still deploys a node per batch
+        assert_eq!(
+            plan_batches(
+                selected(&mut hive),
+                Some(NodeAmount::Percent(10)),
+                false,
+                &HashSet::new()
+            )
+            .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_plan_batches_per_tag() {
+        let mut hive = nodes(&[&["web"], &["web"], &["db", "web"], &["db"], &[]]);
+
+        // node-2 is batched under the selected `web` tag, instead of its
+        // first tag `db`
+        assert_eq!(
+            names(&plan_batches(
+                selected(&mut hive),
+                Some(NodeAmount::Count(1)),
+                true,
+                &HashSet::from(["web".to_string()])
+            )),
+            vec![
+                vec!["node-4", "node-3", "node-0"],
+                vec!["node-1"],
+                vec!["node-2"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_order_batches() {
+        let mut hive = nodes(&[&[], &[], &[], &[]]);
+        let node_names = hive
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        // node-0 depends on node-2, which depends on node-3
+        let dependencies = HashMap::from([
+            (&node_names[0], vec![node_names[2].clone()]),
+            (&node_names[1], vec![]),
+            (&node_names[2], vec![node_names[3].clone()]),
+            (&node_names[3], vec![]),
+        ]);
+        let batches = plan_batches(
+            selected(&mut hive),
+            Some(NodeAmount::Count(1)),
+            false,
+            &HashSet::new(),
+        );
+
+        assert_eq!(
+            names(&order_batches(batches, &dependencies)),
+            vec![
+                vec!["node-1"],
+                vec!["node-3"],
+                vec!["node-2"],
+                vec!["node-0"]
+            ]
+        );
+    }
+}
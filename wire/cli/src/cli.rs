@@ -11,6 +11,8 @@ use lib::SubCommandModifiers;
 use lib::hive::Hive;
 use lib::hive::node::{Goal as HiveGoal, HandleUnreachable, Name, SwitchToConfigurationGoal};
 
+use crate::output::OutputFormat;
+
 use std::io::IsTerminal;
 use std::{
     fmt::{self, Display, Formatter},
@@ -53,6 +55,14 @@ pub struct Cli {
     #[arg(long, global = true, default_value_t = false)]
     pub show_trace: bool,
 
+    /// SSH implementation used to run commands on nodes.
+    ///
+    /// `native` keeps a single session open per node for the whole run, and
+    /// never prompts, so privilege escalation must not ask for a password.
+    #[cfg(feature = "native-ssh")]
+    #[arg(long, global = true, value_enum, default_value = "openssh")]
+    pub ssh_backend: SshBackendArg,
+
     #[cfg(debug_assertions)]
     #[arg(long, hide = true, global = true)]
     pub markdown_help: bool,
@@ -93,6 +103,39 @@ fn more_than_zero(s: &str) -> Result<usize, String> {
     number_range(s, 1, usize::MAX)
 }
 
+/// A number of nodes, either absolute or relative to the amount of selected
+/// nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeAmount {
+    Count(usize),
+    Percent(usize),
+}
+
+impl NodeAmount {
+    /// Resolves the amount against `total` nodes, rounding percentages down.
+    pub const fn of(self, total: usize) -> usize {
+        match self {
+            Self::Count(count) => count,
+            Self::Percent(percent) => total * percent / 100,
+        }
+    }
+}
+
+fn node_amount(s: &str, min: usize) -> Result<NodeAmount, String> {
+    match s.strip_suffix('%') {
+        Some(percent) => number_range(percent, min, 100).map(NodeAmount::Percent),
+        None => number_range(s, min, usize::MAX).map(NodeAmount::Count),
+    }
+}
+
+fn batch_size(s: &str) -> Result<NodeAmount, String> {
+    node_amount(s, 1)
+}
+
+fn failure_budget(s: &str) -> Result<NodeAmount, String> {
+    node_amount(s, 0)
+}
+
 #[derive(Clone)]
 pub enum HandleUnreachableArg {
     Ignore,
@@ -121,6 +164,26 @@ impl clap::ValueEnum for HandleUnreachableArg {
     }
 }
 
+#[cfg(feature = "native-ssh")]
+#[derive(Clone, Debug, ValueEnum)]
+pub enum SshBackendArg {
+    /// Shell out to `ssh`
+    #[value(name = "openssh")]
+    OpenSsh,
+    /// Use the built-in SSH client
+    Native,
+}
+
+#[cfg(feature = "native-ssh")]
+impl From<SshBackendArg> for lib::SshBackend {
+    fn from(value: SshBackendArg) -> Self {
+        match value {
+            SshBackendArg::OpenSsh => Self::OpenSsh,
+            SshBackendArg::Native => Self::Native,
+        }
+    }
+}
+
 impl From<HandleUnreachableArg> for HandleUnreachable {
     fn from(value: HandleUnreachableArg) -> Self {
         match value {
@@ -130,6 +193,7 @@ impl From<HandleUnreachableArg> for HandleUnreachable {
     }
 }
 
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Args)]
 pub struct ApplyArgs {
     #[arg(value_enum, default_value_t)]
@@ -157,6 +221,45 @@ pub struct ApplyArgs {
     #[arg(short, long, default_value_t = false)]
     pub reboot: bool,
 
+    /// Replace system profiles that were not set by wire.
+    ///
+    /// Overrides `deployment.replaceUnknownProfiles`.
+    #[arg(long, default_value_t = false)]
+    pub replace_unknown_profiles: bool,
+
+    /// Evaluate every node, instead of reusing the top levels of a previous
+    /// run whose locked flake source was identical.
+    #[arg(long, default_value_t = false)]
+    pub no_eval_cache: bool,
+
+    /// Build the nodes of a batch that are built locally in a single `nix build`.
+    ///
+    /// Nix then schedules the derivations of those nodes together. Each node
+    /// still pushes and activates on its own once the build finished.
+    #[arg(long, default_value_t = false)]
+    pub build_together: bool,
+
+    /// Deploy in batches of this many nodes, or a percentage of the selected
+    /// nodes, such as `5` or `10%`.
+    ///
+    /// Each batch, including its health checks, must finish before the next
+    /// one starts. By default all nodes are deployed at once.
+    #[arg(long, value_name = "N | N%", value_parser = batch_size)]
+    pub batch_size: Option<NodeAmount>,
+
+    /// Size batches separately for each tag.
+    ///
+    /// Nodes are grouped by the `--on` tag they matched, otherwise by their
+    /// first tag alphabetically. Every batch then takes `--batch-size` nodes
+    /// from each group, so no tag is deployed all at once.
+    #[arg(long, default_value_t = false, requires = "batch_size")]
+    pub batch_per_tag: bool,
+
+    /// Skip the remaining batches once more than this many nodes, or
+    /// percentage of the selected nodes, have failed. Requires `--batch-size`.
+    #[arg(long, value_name = "N | N%", value_parser = failure_budget, requires = "batch_size")]
+    pub max_failures: Option<NodeAmount>,
+
     /// How to handle an unreachable node in the ping step.
     ///
     /// This only effects the ping step.
@@ -170,12 +273,153 @@ pub struct ApplyArgs {
     /// Vulnerable to man-in-the-middle attacks, use with caution.
     #[arg(long, default_value_t = false)]
     pub ssh_accept_host: bool,
+
+    /// Output format of the deployment.
+    ///
+    /// `json` writes one JSON event per line to stdout, while logs are still
+    /// written to stderr.
+    #[arg(long, value_enum, default_value_t)]
+    pub output: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct DiffArgs {
+    /// List of literal node names, a literal `-`, or `@` prefixed tags.
+    ///
+    /// `-` will read additional values from stdin, separated by whitespace.
+    /// Any `-` implies `--non-interactive`.
+    #[arg(short, long, value_name = "NODE | @TAG | `-`", num_args = 1..)]
+    pub on: Vec<ApplyTarget>,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// Overrides deployment.buildOnTarget.
+    #[arg(short, long, value_name = "NODE")]
+    pub always_build_local: Vec<String>,
+
+    /// Evaluate every node, instead of reusing the top levels of a previous
+    /// run whose locked flake source was identical.
+    #[arg(long, default_value_t = false)]
+    pub no_eval_cache: bool,
+
+    /// Build the nodes that are built locally in a single `nix build`.
+    ///
+    /// Nix then schedules the derivations of those nodes together. Each node
+    /// is diffed against its current system once the build finished.
+    #[arg(long, default_value_t = false)]
+    pub build_together: bool,
+
+    /// How to handle an unreachable node in the ping step.
+    #[arg(long, default_value_t = HandleUnreachableArg::FailNode)]
+    pub handle_unreachable: HandleUnreachableArg,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+impl From<DiffArgs> for ApplyArgs {
+    fn from(value: DiffArgs) -> Self {
+        ApplyArgs {
+            goal: Goal::Diff,
+            on: value.on,
+            parallel: value.parallel,
+            no_keys: true,
+            always_build_local: value.always_build_local,
+            reboot: false,
+            replace_unknown_profiles: false,
+            no_eval_cache: value.no_eval_cache,
+            build_together: value.build_together,
+            batch_size: None,
+            batch_per_tag: false,
+            max_failures: None,
+            handle_unreachable: value.handle_unreachable,
+            ssh_accept_host: value.ssh_accept_host,
+            output: OutputFormat::Human,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct VerifyKeysArgs {
+    /// List of literal node names, a literal `-`, or `@` prefixed tags.
+    ///
+    /// `-` will read additional values from stdin, separated by whitespace.
+    /// Any `-` implies `--non-interactive`.
+    #[arg(short, long, value_name = "NODE | @TAG | `-`", num_args = 1..)]
+    pub on: Vec<ApplyTarget>,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// How to handle an unreachable node in the ping step.
+    #[arg(long, default_value_t = HandleUnreachableArg::FailNode)]
+    pub handle_unreachable: HandleUnreachableArg,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+impl From<VerifyKeysArgs> for ApplyArgs {
+    fn from(value: VerifyKeysArgs) -> Self {
+        ApplyArgs {
+            goal: Goal::VerifyKeys,
+            on: value.on,
+            parallel: value.parallel,
+            no_keys: false,
+            always_build_local: Vec::new(),
+            reboot: false,
+            replace_unknown_profiles: false,
+            no_eval_cache: false,
+            build_together: false,
+            batch_size: None,
+            batch_per_tag: false,
+            max_failures: None,
+            handle_unreachable: value.handle_unreachable,
+            ssh_accept_host: value.ssh_accept_host,
+            output: OutputFormat::Human,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommands {
+    /// Compare the keys on each node against the hive, without uploading
+    ///
+    /// Reports every key that is missing, or whose content, owner, group or
+    /// mode differ. Nodes with such keys fail.
+    Verify(VerifyKeysArgs),
+}
+
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// Only show deployments of this node
+    #[arg(value_name = "NODE")]
+    pub node: Option<String>,
+
+    /// Number of deployments to show, newest first
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+
+    /// Return in JSON format
+    #[arg(short, long, default_value_t = false)]
+    pub json: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Deploy nodes
     Apply(ApplyArgs),
+    /// Show how each node's closure would change, without applying
+    Diff(DiffArgs),
     /// Inspect hive
     #[clap(visible_alias = "show")]
     Inspect {
@@ -183,6 +427,13 @@ pub enum Commands {
         #[arg(short, long, default_value_t = false)]
         json: bool,
     },
+    /// Show previous deployments
+    History(HistoryArgs),
+    /// Audit deployment keys
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommands,
+    },
     /// Generates shell completions
     #[clap(hide = true)]
     Completions {
@@ -209,6 +460,12 @@ pub enum Goal {
     Test,
     /// Show what would be done if this configuration were activated.
     DryActivate,
+    /// Only reachable through `wire diff`
+    #[value(skip)]
+    Diff,
+    /// Only reachable through `wire keys verify`
+    #[value(skip)]
+    VerifyKeys,
 }
 
 impl TryFrom<Goal> for HiveGoal {
@@ -231,6 +488,8 @@ impl TryFrom<Goal> for HiveGoal {
                 SwitchToConfigurationGoal::DryActivate,
             )),
             Goal::Keys => Ok(HiveGoal::Keys),
+            Goal::Diff => Ok(HiveGoal::Diff),
+            Goal::VerifyKeys => Ok(HiveGoal::VerifyKeys),
         }
     }
 }
@@ -245,9 +504,63 @@ impl ToSubCommandModifiers for Cli {
             show_trace: self.show_trace,
             non_interactive: self.non_interactive,
             ssh_accept_host: match &self.command {
-                Commands::Apply(args) if args.ssh_accept_host => lib::StrictHostKeyChecking::No,
+                Commands::Apply(ApplyArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Diff(DiffArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Keys {
+                    command:
+                        KeysCommands::Verify(VerifyKeysArgs {
+                            ssh_accept_host: true,
+                            ..
+                        }),
+                } => lib::StrictHostKeyChecking::No,
                 _ => lib::StrictHostKeyChecking::default(),
             },
+            #[cfg(feature = "native-ssh")]
+            ssh_backend: self.ssh_backend.clone().into(),
+            #[cfg(not(feature = "native-ssh"))]
+            ssh_backend: lib::SshBackend::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_amount_of() {
+        assert_eq!(NodeAmount::Count(3).of(10), 3);
+        assert_eq!(NodeAmount::Count(20).of(10), 20);
+        // rounded down
+        assert_eq!(NodeAmount::Percent(25).of(10), 2);
+        assert_eq!(NodeAmount::Percent(10).of(5), 0);
+        assert_eq!(NodeAmount::Percent(0).of(10), 0);
+        assert_eq!(NodeAmount::Percent(100).of(7), 7);
+    }
+
+    #[test]
+    fn test_node_amount_parse() {
+        assert_eq!(batch_size("5"), Ok(NodeAmount::Count(5)));
+        assert_eq!(batch_size("10%"), Ok(NodeAmount::Percent(10)));
+        assert!(batch_size("0").is_err());
+        assert!(batch_size("0%").is_err());
+        assert!(batch_size("101%").is_err());
+        assert_eq!(failure_budget("0%"), Ok(NodeAmount::Percent(0)));
+        assert!(failure_budget("150%").is_err());
+    }
+
+    #[test]
+    fn test_max_failures_requires_batch_size() {
+        assert!(Cli::try_parse_from(["wire", "apply", "--max-failures", "1"]).is_err());
+        assert!(
+            Cli::try_parse_from(["wire", "apply", "--batch-size", "2", "--max-failures", "1"])
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+use lib::hive::{HiveLocation, node::StepTiming};
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{cli::HistoryArgs, output::NodeOutcome};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum HistoryError {
+    #[error("neither $XDG_STATE_HOME nor $HOME is set, cannot find the deployment history")]
+    NoStateDirectory,
+
+    #[error("failed to write deployment history to {}", .0.display())]
+    Write(PathBuf, #[source] io::Error),
+
+    #[error("failed to serialize deployment history")]
+    Serialize(#[source] serde_json::Error),
+}
+
+/// A single `wire apply`, stored as one JSON file in `history_directory()`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Deployment {
+    /// Seconds since the unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub user: Option<String>,
+    pub location: String,
+    /// `HEAD` of the git repository containing the hive, suffixed with
+    /// `-dirty` when it had uncommitted changes.
+    pub revision: Option<String>,
+    pub goal: String,
+    pub nodes: Vec<NodeRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeRecord {
+    pub name: String,
+    pub status: NodeOutcome,
+    /// The system's toplevel store path, if it was built
+    pub toplevel: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<StepTiming>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn history_directory() -> Result<PathBuf, HistoryError> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|state| state.join("wire/history"))
+        .ok_or(HistoryError::NoStateDirectory)
+}
+
+pub fn location(location: &HiveLocation) -> String {
+    match location {
+        HiveLocation::HiveNix(path) => path.display().to_string(),
+        HiveLocation::Flake(flake) => flake.clone(),
+    }
+}
+
+/// The revision `nix flake metadata --json` locked the flake to. Unpinned
+/// references such as `github:owner/repo` move between runs, so the location
+/// alone does not say what was deployed.
+fn locked_revision(metadata: &[u8]) -> Option<String> {
+    let metadata: serde_json::Value = serde_json::from_slice(metadata).ok()?;
+
+    metadata
+        .pointer("/locked/rev")
+        .or_else(|| metadata.get("dirtyRev"))?
+        .as_str()
+        .map(str::to_string)
+}
+
+fn flake_revision(flake: &str) -> Option<String> {
+    Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "--extra-experimental-features",
+            "flakes",
+            "flake",
+            "metadata",
+            "--json",
+            flake,
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| locked_revision(&output.stdout))
+}
+
+/// Returns `None` when the revision is unknown, for example for a flake
+/// that is not in git.
+pub fn revision(location: &HiveLocation) -> Option<String> {
+    let directory = match location {
+        HiveLocation::HiveNix(path) => path.parent()?,
+        HiveLocation::Flake(flake) if Path::new(flake).is_dir() => Path::new(flake),
+        HiveLocation::Flake(flake) => return flake_revision(flake),
+    };
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(directory)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let mut revision = git(&["rev-parse", "HEAD"])?;
+
+    if git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty()) {
+        revision.push_str("-dirty");
+    }
+
+    Some(revision)
+}
+
+pub fn user() -> Option<String> {
+    env::var("USER").or_else(|_| env::var("LOGNAME")).ok()
+}
+
+impl Deployment {
+    pub fn save(&self) -> Result<PathBuf, HistoryError> {
+        let directory = history_directory()?;
+        let path = directory.join(format!("{}-{}.json", self.started_at, std::process::id()));
+        let contents = serde_json::to_vec_pretty(self).map_err(HistoryError::Serialize)?;
+
+        fs::create_dir_all(&directory).map_err(|err| HistoryError::Write(directory, err))?;
+        fs::write(&path, contents).map_err(|err| HistoryError::Write(path.clone(), err))?;
+
+        Ok(path)
+    }
+
+    fn succeeded(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.status == NodeOutcome::Succeeded)
+            .count()
+    }
+}
+
+/// Reads every recorded deployment, newest first. Unreadable entries are
+/// skipped with a warning.
+fn load() -> Result<Vec<Deployment>> {
+    let directory = history_directory()?;
+
+    let entries = match fs::read_dir(&directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).into_diagnostic(),
+    };
+
+    let mut deployments = Vec::new();
+
+    for entry in entries {
+        let path = entry.into_diagnostic()?.path();
+
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+
+        match fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| serde_json::from_slice(&contents).map_err(|err| err.to_string()))
+        {
+            Ok(deployment) => deployments.push(deployment),
+            Err(err) => warn!(
+                "Skipping unreadable history entry {}: {err}",
+                path.display()
+            ),
+        }
+    }
+
+    deployments.sort_by_key(|deployment: &Deployment| std::cmp::Reverse(deployment.started_at));
+
+    Ok(deployments)
+}
+
+/// Formats seconds since the unix epoch as a UTC date and time.
+fn format_timestamp(timestamp: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp / 86_400 + 719_468;
+    let seconds = timestamp % 86_400;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn show(args: &HistoryArgs) -> Result<()> {
+    let mut deployments = load()?;
+
+    if let Some(node) = &args.node {
+        deployments.retain_mut(|deployment| {
+            deployment.nodes.retain(|record| record.name == *node);
+            !deployment.nodes.is_empty()
+        });
+    }
+
+    deployments.truncate(args.limit);
+
+    if args.json {
+        println!("{}", serde_json::to_string(&deployments).into_diagnostic()?);
+        return Ok(());
+    }
+
+    for deployment in &deployments {
+        let revision = deployment
+            .revision
+            .as_deref()
+            .unwrap_or(&deployment.location);
+        let user = deployment.user.as_deref().unwrap_or("unknown user");
+
+        if args.node.is_some() {
+            // only the selected node remains
+            for record in &deployment.nodes {
+                println!(
+                    "{}  {}  {:?}  {}  {revision}  by {user}",
+                    format_timestamp(deployment.started_at),
+                    deployment.goal,
+                    record.status,
+                    record.toplevel.as_deref().unwrap_or("-"),
+                );
+            }
+
+            continue;
+        }
+
+        println!(
+            "{}  {}  {}/{} succeeded  {revision}  by {user}",
+            format_timestamp(deployment.started_at),
+            deployment.goal,
+            deployment.succeeded(),
+            deployment.nodes.len(),
+        );
+        println!(
+            "    {}",
+            deployment
+                .nodes
+                .iter()
+                .map(|record| format!("{} ({:?})", record.name, record.status))
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        // leap days, including a century that is a leap year
+        assert_eq!(format_timestamp(1_709_210_096), "2024-02-29 12:34:56 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        // 2100 is not a leap year
+        assert_eq!(format_timestamp(4_107_542_399), "2100-02-28 23:59:59 UTC");
+        assert_eq!(format_timestamp(4_107_542_400), "2100-03-01 00:00:00 UTC");
+        // year boundaries
+        assert_eq!(format_timestamp(1_704_067_199), "2023-12-31 23:59:59 UTC");
+        assert_eq!(format_timestamp(1_704_067_200), "2024-01-01 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_locked_revision() {
+        let locked = br#"{
+            "original": { "owner": "mrshmllow", "repo": "wire", "type": "github" },
+            "locked": {
+                "owner": "mrshmllow",
+                "repo": "wire",
+                "rev": "f33d80c15b17c85d557d533441609a59a2210941",
+                "type": "github"
+            }
+        }"#;
+        let dirty = br#"{
+            "dirtyRev": "da2060bdc1c9bc35acc4eafa265ba6b6c64f9926-dirty",
+            "locked": { "type": "git", "url": "file:///srv/hive" }
+        }"#;
+
+        assert_eq!(
+            locked_revision(locked).as_deref(),
+            Some("f33d80c15b17c85d557d533441609a59a2210941")
+        );
+        assert_eq!(
+            locked_revision(dirty).as_deref(),
+            Some("da2060bdc1c9bc35acc4eafa265ba6b6c64f9926-dirty")
+        );
+        assert_eq!(
+            locked_revision(br#"{ "locked": { "type": "tarball" } }"#),
+            None
+        );
+    }
+}
@@ -8,6 +8,7 @@ use std::process::Command;
 
 use crate::cli::Cli;
 use crate::cli::ToSubCommandModifiers;
+use crate::output::OutputFormat;
 use crate::tracing_setup::setup_logging;
 use clap::CommandFactory;
 use clap::Parser;
@@ -24,6 +25,8 @@ extern crate enum_display_derive;
 
 mod apply;
 mod cli;
+mod history;
+mod output;
 mod tracing_setup;
 
 #[cfg(feature = "dhat-heap")]
@@ -38,7 +41,14 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     let modifiers = args.to_subcommand_modifiers();
-    setup_logging(&args.verbose, !&args.no_progress);
+    let json_output = matches!(
+        &args.command,
+        cli::Commands::Apply(cli::ApplyArgs {
+            output: OutputFormat::Json,
+            ..
+        })
+    );
+    setup_logging(&args.verbose, !&args.no_progress, json_output);
 
     #[cfg(debug_assertions)]
     if args.markdown_help {
@@ -46,6 +56,11 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // the history is local, it needs neither nix nor a hive
+    if let cli::Commands::History(history_args) = &args.command {
+        return history::show(history_args);
+    }
+
     if !matches!(args.command, cli::Commands::Completions { .. }) && !check_nix_available() {
         miette::bail!("Nix is not available on this system.");
     }
@@ -57,6 +72,16 @@ async fn main() -> Result<()> {
             let mut hive = Hive::new_from_path(&location, modifiers).await?;
             apply::apply(&mut hive, location, apply_args, modifiers).await?;
         }
+        cli::Commands::Diff(diff_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            apply::apply(&mut hive, location, diff_args.into(), modifiers).await?;
+        }
+        cli::Commands::Keys {
+            command: cli::KeysCommands::Verify(verify_args),
+        } => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            apply::apply(&mut hive, location, verify_args.into(), modifiers).await?;
+        }
         cli::Commands::Inspect { json } => println!("{}", {
             let hive = Hive::new_from_path(&location, modifiers).await?;
             if json {
@@ -66,6 +91,7 @@ async fn main() -> Result<()> {
                 format!("{hive}")
             }
         }),
+        cli::Commands::History(..) => unreachable!("handled before locating the hive"),
         cli::Commands::Completions { shell } => {
             let mut cmd = Cli::command();
             let name = cmd.clone();
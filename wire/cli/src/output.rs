@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use lib::errors::HiveLibError;
+use lib::hive::steps::diff::SystemDiff;
+use lib::hive::steps::keys::KeyDrift;
+use miette::Diagnostic;
+use owo_colors::{OwoColorize, Stream};
+use serde::{Deserialize, Serialize};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable logs
+    #[default]
+    Human,
+    /// Newline delimited JSON events on stdout, logs stay on stderr
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeOutcome {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorInfo {
+    /// The miette diagnostic code, such as `wire::command::CommandFailed`
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl From<&HiveLibError> for ErrorInfo {
+    fn from(error: &HiveLibError) -> Self {
+        Self {
+            code: error.code().map(|code| code.to_string()),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NodeResult<'a> {
+    pub node: &'a str,
+    pub status: NodeOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorInfo>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    NodeSelected {
+        node: &'a str,
+    },
+    BatchStarted {
+        batch: usize,
+        batches: usize,
+        nodes: Vec<&'a str>,
+    },
+    StepStarted {
+        node: &'a str,
+        step: &'a str,
+        progress: &'a str,
+    },
+    StepFinished {
+        node: &'a str,
+        step: &'a str,
+        duration_ms: u64,
+        success: bool,
+    },
+    KeyUploaded {
+        node: &'a str,
+        key: &'a str,
+        /// `created` or `updated`
+        status: Option<&'a str>,
+    },
+    Log {
+        node: &'a str,
+        step: Option<&'a str>,
+        level: &'a str,
+        message: &'a str,
+    },
+    /// A message logged by nix
+    NixLog {
+        node: &'a str,
+        step: Option<&'a str>,
+        level: &'a str,
+        message: &'a str,
+    },
+    /// The progress of a nix activity, such as building or copying paths
+    NixProgress {
+        node: &'a str,
+        step: Option<&'a str>,
+        activity: u64,
+        done: u64,
+        expected: u64,
+        running: u64,
+        failed: u64,
+    },
+    Diff {
+        node: &'a str,
+        #[serde(flatten)]
+        diff: &'a SystemDiff,
+    },
+    KeysVerified {
+        node: &'a str,
+        keys: &'a [KeyDrift],
+    },
+    NodeFinished(NodeResult<'a>),
+    Summary {
+        succeeded: usize,
+        failed: usize,
+        skipped: usize,
+        nodes: Vec<NodeResult<'a>>,
+    },
+}
+
+fn json_enabled() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+pub fn enable_json() {
+    JSON_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+/// Writes the event as a single line to stdout, if `--output json` is used.
+pub fn emit(event: &Event) {
+    if !json_enabled() {
+        return;
+    }
+
+    let Ok(line) = serde_json::to_string(event) else {
+        return;
+    };
+
+    let _ = writeln!(std::io::stdout().lock(), "{line}");
+}
+
+/// Prints the closure diff of `wire diff` to stdout, or emits it as an event.
+pub fn print_diff(node: &str, diff: &SystemDiff) {
+    if json_enabled() {
+        emit(&Event::Diff { node, diff });
+        return;
+    }
+
+    let _ = writeln!(
+        std::io::stdout().lock(),
+        "{} {}:\n{}",
+        node.bold(),
+        format!("({} → {})", diff.current, diff.built)
+            .if_supports_color(Stream::Stdout, |x| x.dimmed()),
+        diff.closure
+    );
+}
+
+/// Prints the keys checked by `wire keys verify` to stdout, or emits them as
+/// an event.
+pub fn print_key_drift(node: &str, keys: &[KeyDrift]) {
+    if json_enabled() {
+        emit(&Event::KeysVerified { node, keys });
+        return;
+    }
+
+    let _ = writeln!(
+        std::io::stdout().lock(),
+        "{} keys:\n{}",
+        node.bold(),
+        keys.iter().join("\n")
+    );
+}
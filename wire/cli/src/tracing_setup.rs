@@ -8,9 +8,12 @@ use std::{
 };
 
 use clap_verbosity_flag::{LogLevel, Verbosity};
-use lib::{STDIN_CLOBBER_LOCK, status::STATUS};
+use lib::{STDIN_CLOBBER_LOCK, commands::NIX_TARGET, status::STATUS};
 use owo_colors::{OwoColorize, Stream, Style};
-use tracing::{Level, Subscriber};
+use serde_json::{Map, Value};
+
+use crate::output::{self, Event, emit};
+use tracing::{Level, Subscriber, field::Visit, level_filters::LevelFilter};
 use tracing_log::AsTrace;
 use tracing_subscriber::{
     Layer,
@@ -231,6 +234,177 @@ where
     }
 }
 
+/// The plain name of the node an `execute` span belongs to
+struct JsonNodeName(String);
+/// Collects the fields of a span or event as JSON values
+#[derive(Default)]
+struct JsonFieldVisitor(Map<String, Value>);
+/// `JsonLayer` translates node events into `--output json` events. Events
+/// that are not specifically handled are emitted as logs when `filter` allows
+/// them.
+struct JsonLayer {
+    filter: LevelFilter,
+}
+
+impl JsonFieldVisitor {
+    fn str(&self, name: &str) -> Option<&str> {
+        self.0.get(name).and_then(Value::as_str)
+    }
+}
+
+impl Visit for JsonFieldVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::String(format!("{value:?}")),
+        );
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0
+            .insert(field.name().to_string(), Value::String(value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::Bool(value));
+    }
+}
+
+impl JsonLayer {
+    /// Emits an event traced from nix's logs, see `lib::commands::NIX_TARGET`
+    fn on_nix_event(
+        &self,
+        node: &str,
+        step: Option<&str>,
+        visitor: &JsonFieldVisitor,
+        level: Level,
+    ) {
+        let count = |name| visitor.0.get(name).and_then(Value::as_u64);
+
+        if let Some(activity) = count("activity") {
+            emit(&Event::NixProgress {
+                node,
+                step,
+                activity,
+                done: count("done").unwrap_or_default(),
+                expected: count("expected").unwrap_or_default(),
+                running: count("running").unwrap_or_default(),
+                failed: count("failed").unwrap_or_default(),
+            });
+        } else if self.filter >= level {
+            emit(&Event::NixLog {
+                node,
+                step,
+                level: level.as_str(),
+                message: visitor.str("message").unwrap_or_default(),
+            });
+        }
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        // see `GoalExecutor::execute`
+        if attrs.metadata().name() != "execute" {
+            return;
+        }
+
+        let mut visitor = JsonFieldVisitor::default();
+        attrs.record(&mut visitor);
+
+        if let (Some(span), Some(node)) = (ctx.span(id), visitor.str("node")) {
+            span.extensions_mut().insert(JsonNodeName(node.to_string()));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+
+        let mut node = None;
+        let mut span_name = None;
+
+        for span in scope.from_root() {
+            if let Some(JsonNodeName(name)) = span.extensions().get::<JsonNodeName>() {
+                node = Some(name.clone());
+            } else if node.is_some() && span_name.is_none() {
+                span_name = Some(span.name());
+            }
+        }
+
+        // only events of a node's execution are emitted
+        let Some(node) = node else {
+            return;
+        };
+
+        let mut visitor = JsonFieldVisitor::default();
+        event.record(&mut visitor);
+
+        let step = visitor.str("step");
+        let level = event.metadata().level();
+
+        if event.metadata().target() == NIX_TARGET {
+            self.on_nix_event(&node, step.or(span_name), &visitor, *level);
+            return;
+        }
+
+        let output_event = if let (Some(step), Some(progress)) = (step, visitor.str("progress")) {
+            Event::StepStarted {
+                node: &node,
+                step,
+                progress,
+            }
+        } else if let (Some(step), Some(duration_ms)) =
+            (step, visitor.0.get("duration_ms").and_then(Value::as_u64))
+        {
+            Event::StepFinished {
+                node: &node,
+                step,
+                duration_ms,
+                success: visitor
+                    .0
+                    .get("success")
+                    .and_then(Value::as_bool)
+                    .unwrap_or_default(),
+            }
+        } else if let Some(key) = visitor.str("key") {
+            Event::KeyUploaded {
+                node: &node,
+                key,
+                status: visitor.str("status"),
+            }
+        } else if self.filter >= *level {
+            Event::Log {
+                node: &node,
+                step: step.or(span_name),
+                level: level.as_str(),
+                message: visitor.str("message").unwrap_or_default(),
+            }
+        } else {
+            return;
+        };
+
+        emit(&output_event);
+    }
+}
+
 async fn status_tick_worker() {
     let mut interval = tokio::time::interval(Duration::from_secs(1));
     let mut stderr = stderr();
@@ -251,9 +425,22 @@ async fn status_tick_worker() {
 
 /// Set up logging for the application
 /// Uses `WireFieldFormat` if -v was never passed
-pub fn setup_logging<L: LogLevel>(verbosity: &Verbosity<L>, show_progress: bool) {
+/// Adds `JsonLayer` if `json_output` is set
+pub fn setup_logging<L: LogLevel>(
+    verbosity: &Verbosity<L>,
+    show_progress: bool,
+    json_output: bool,
+) {
     let filter = verbosity.log_level_filter().as_trace();
-    let registry = tracing_subscriber::registry();
+
+    if json_output {
+        output::enable_json();
+    }
+
+    // step timings and key uploads are emitted at the debug level
+    let json_layer =
+        json_output.then(|| JsonLayer { filter }.with_filter(filter.max(LevelFilter::DEBUG)));
+    let registry = tracing_subscriber::registry().with(json_layer);
 
     STATUS.lock().show_progress(show_progress);
 
@@ -4,3 +4,38 @@
 pub mod keys {
     include!(concat!(env!("OUT_DIR"), "/key_agent.keys.rs"));
 }
+
+/// Version of the protocol between wire and the agent. Both sides refuse to
+/// talk to a different version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the protocol, negotiated with `keys::Hello`.
+pub mod capabilities {
+    /// Reporting a `KeyState` for an inspecting manifest
+    pub const INSPECT: &str = "inspect";
+    /// Removing stale keys
+    pub const REMOVE_STALE: &str = "remove-stale";
+    /// Receiving keys in frames of at most `KEY_CHUNK_SIZE` bytes
+    pub const CHUNKED_KEYS: &str = "chunked-keys";
+}
+
+/// Every capability this agent supports.
+pub const CAPABILITIES: &[&str] = &[
+    capabilities::INSPECT,
+    capabilities::REMOVE_STALE,
+    capabilities::CHUNKED_KEYS,
+];
+
+/// Printed by the agent, followed by a base64 encoded `Hello`.
+pub const HELLO_PREFIX: &str = "wire-key-agent hello ";
+
+/// Maximum number of key bytes in a single frame, before base64 encoding.
+pub const KEY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Printed by the agent, followed by the destination, for every stale key it
+/// removed.
+pub const REMOVED_KEY_PREFIX: &str = "wire-key-agent removed ";
+
+/// Printed by the agent, followed by a base64 encoded `KeyState`, for every
+/// destination of an inspecting manifest.
+pub const KEY_STATE_PREFIX: &str = "wire-key-agent state ";
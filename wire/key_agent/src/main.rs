@@ -5,18 +5,34 @@
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use futures_util::stream::StreamExt;
-use key_agent::keys::KeySpec;
-use nix::unistd::{Group, User};
+use key_agent::keys::{Hello, KeySpec, KeyState, Manifest};
+use key_agent::{
+    CAPABILITIES, HELLO_PREFIX, KEY_STATE_PREFIX, PROTOCOL_VERSION, REMOVED_KEY_PREFIX,
+};
+use nix::unistd::{Gid, Group, Uid, User};
 use prost::Message;
 use prost::bytes::Bytes;
 use sha2::{Digest, Sha256};
-use std::os::unix::fs::PermissionsExt;
+use std::collections::BTreeSet;
+use std::fs::Permissions;
+use std::io::ErrorKind;
 use std::os::unix::fs::chown;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
 
+/// Destinations of every key written by the agent as root, one per line.
+const MANAGED_KEYS_FILE: &str = "/var/lib/wire/managed-keys";
+
+/// A key written to a temporary file next to its destination, with its final
+/// owner and mode, waiting to be renamed into place.
+struct StagedKey {
+    temporary: PathBuf,
+    destination: PathBuf,
+}
+
 fn create_path(key_path: &Path) -> Result<(), anyhow::Error> {
     let prefix = key_path.parent().unwrap();
     std::fs::create_dir_all(prefix)?;
@@ -24,6 +40,365 @@ fn create_path(key_path: &Path) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Keys written without privilege escalation are tracked in the user's state
+/// directory instead, as they cannot write to `MANAGED_KEYS_FILE`.
+fn managed_keys_file() -> Result<PathBuf, anyhow::Error> {
+    if Uid::effective().is_root() {
+        return Ok(PathBuf::from(MANAGED_KEYS_FILE));
+    }
+
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|state| state.join("wire/managed-keys"))
+        .ok_or_else(|| anyhow::anyhow!("neither $XDG_STATE_HOME nor $HOME is set"))
+}
+
+/// A hidden file next to `destination`. The agent's pid keeps concurrent
+/// agents writing the same destination from clobbering each other's files.
+fn sibling_path(destination: &Path, kind: &str) -> PathBuf {
+    let file_name = destination
+        .file_name()
+        .expect("key destination should have a file name")
+        .to_string_lossy();
+
+    destination.with_file_name(format!(".{file_name}.wire-{kind}-{}", std::process::id()))
+}
+
+fn temporary_path(destination: &Path) -> PathBuf {
+    sibling_path(destination, "tmp")
+}
+
+/// Makes a rename into `path`'s directory durable.
+fn sync_parent(path: &Path) -> Result<(), anyhow::Error> {
+    std::fs::File::open(path.parent().unwrap())?.sync_all()?;
+
+    Ok(())
+}
+
+async fn next_frame<R: AsyncRead + Unpin>(
+    framed: &mut FramedRead<R, LengthDelimitedCodec>,
+    expected: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let frame = framed
+        .next()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("stdin closed, expected {expected}"))??;
+
+    Ok(BASE64_STANDARD.decode(frame)?)
+}
+
+/// Writes the key's frames to `file` as they arrive, returning their digest.
+async fn receive_key_data<R: AsyncRead + Unpin>(
+    framed: &mut FramedRead<R, LengthDelimitedCodec>,
+    spec: &KeySpec,
+    file: &mut tokio::fs::File,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut hasher = Sha256::new();
+    let mut remaining = spec.length;
+
+    while remaining > 0 {
+        let chunk = next_frame(framed, "key data to come after its spec").await?;
+
+        remaining = remaining
+            .checked_sub(chunk.len() as u64)
+            .ok_or_else(|| anyhow::anyhow!("received more than {} bytes", spec.length))?;
+
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Streams a key into a temporary file next to its destination. The file is
+/// added to `staged` before it is created, so a partially received key is
+/// rolled back too.
+async fn stage_key<R: AsyncRead + Unpin>(
+    framed: &mut FramedRead<R, LengthDelimitedCodec>,
+    spec: &KeySpec,
+    staged: &mut Vec<StagedKey>,
+) -> Result<(), anyhow::Error> {
+    let destination = PathBuf::from(&spec.destination);
+    create_path(&destination)?;
+
+    let temporary = temporary_path(&destination);
+
+    staged.push(StagedKey {
+        temporary: temporary.clone(),
+        destination,
+    });
+
+    // created without any permissions, so the key is never readable by
+    // anyone before the final owner and mode are set
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o000)
+        .open(&temporary)
+        .await?;
+
+    let user = User::from_name(&spec.user)?;
+    let group = Group::from_name(&spec.group)?;
+
+    // Default uid/gid to 0.
+    let uid: u32 = user.map_or(0, |user| user.uid.into());
+    let gid: u32 = group.map_or(0, |group| group.gid.into());
+    let metadata = file.metadata().await?;
+
+    // only root may give files away, so the agent running as the SSH user
+    // leaves the owner it already has alone
+    if metadata.uid() != uid || metadata.gid() != gid {
+        chown(&temporary, Some(uid), Some(gid))?;
+    }
+
+    file.set_permissions(Permissions::from_mode(spec.permissions))
+        .await?;
+
+    let digest = receive_key_data(framed, spec, &mut file).await?;
+
+    if digest != spec.digest {
+        return Err(anyhow::anyhow!(
+            "digest of {spec:?} did not match {digest:?}, was the key modified during the upload?"
+        ));
+    }
+
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+/// Receives and stages every key the manifest declared. Nothing is written to
+/// a key's destination yet.
+async fn receive_keys<R: AsyncRead + Unpin>(
+    framed: &mut FramedRead<R, LengthDelimitedCodec>,
+    manifest: &Manifest,
+    staged: &mut Vec<StagedKey>,
+) -> Result<(), anyhow::Error> {
+    for _ in 0..manifest.keys {
+        let spec_bytes = next_frame(framed, "as many keys as the manifest declared").await?;
+        let spec = KeySpec::decode(Bytes::from(spec_bytes))?;
+
+        println!(
+            "Writing {}, {:?} bytes of data",
+            pretty_keyspec(&spec),
+            spec.length
+        );
+
+        stage_key(framed, &spec, staged).await?;
+    }
+
+    Ok(())
+}
+
+/// Hashes the file in buffered reads, so large keys are never read into
+/// memory at once.
+fn file_digest(path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(
+        &mut std::io::BufReader::new(std::fs::File::open(path)?),
+        &mut hasher,
+    )?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+fn inspect_key(destination: &str) -> Result<KeyState, anyhow::Error> {
+    let metadata = match std::fs::metadata(destination) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(KeyState {
+                destination: destination.to_string(),
+                ..Default::default()
+            });
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let user = User::from_uid(Uid::from_raw(metadata.uid()))?
+        .map_or_else(|| metadata.uid().to_string(), |user| user.name);
+    let group = Group::from_gid(Gid::from_raw(metadata.gid()))?
+        .map_or_else(|| metadata.gid().to_string(), |group| group.name);
+
+    Ok(KeyState {
+        destination: destination.to_string(),
+        exists: true,
+        digest: file_digest(destination)?,
+        user,
+        group,
+        permissions: metadata.mode() & 0o7777,
+    })
+}
+
+fn read_managed_keys(path: &Path) -> Result<BTreeSet<String>, anyhow::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_managed_keys(path: &Path, keys: &BTreeSet<String>) -> Result<(), anyhow::Error> {
+    create_path(path)?;
+
+    let temporary = temporary_path(path);
+
+    std::fs::write(
+        &temporary,
+        keys.iter()
+            .flat_map(|key| [key.as_str(), "\n"])
+            .collect::<String>(),
+    )?;
+    std::fs::File::open(&temporary)?.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    sync_parent(path)?;
+
+    Ok(())
+}
+
+/// Renames a staged key into place. The key previously at the destination is
+/// kept as a hard link, and returned so it can be restored.
+fn commit_key(key: &StagedKey) -> Result<Option<PathBuf>, anyhow::Error> {
+    let backup = sibling_path(&key.destination, "bak");
+
+    let backup = match std::fs::hard_link(&key.destination, &backup) {
+        Ok(()) => Some(backup),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Err(err) = std::fs::rename(&key.temporary, &key.destination) {
+        if let Some(backup) = &backup {
+            let _ = std::fs::remove_file(backup);
+        }
+
+        return Err(err.into());
+    }
+
+    Ok(backup)
+}
+
+/// Puts the key previously at the destination back, or removes the
+/// destination if there was none.
+fn restore_key(destination: &Path, backup: Option<&Path>) -> Result<(), anyhow::Error> {
+    match backup {
+        Some(backup) => std::fs::rename(backup, destination)?,
+        None => std::fs::remove_file(destination)?,
+    }
+
+    sync_parent(destination)
+}
+
+/// Renames every staged key into place, or none of them: if a rename fails,
+/// the keys already renamed are restored and the remaining temporary files
+/// removed. Only keys the agent wrote are recorded in `managed`, so files that
+/// already existed at a destination are never removed as stale.
+fn commit_keys(
+    staged: Vec<StagedKey>,
+    managed: &mut BTreeSet<String>,
+) -> Result<(), anyhow::Error> {
+    let mut committed = Vec::with_capacity(staged.len());
+    let mut staged = staged.into_iter();
+
+    while let Some(key) = staged.next() {
+        match commit_key(&key) {
+            Ok(backup) => committed.push((key, backup)),
+            Err(err) => {
+                for key in std::iter::once(key).chain(staged) {
+                    let _ = std::fs::remove_file(&key.temporary);
+                }
+
+                let unrestored = committed
+                    .iter()
+                    .rev()
+                    .filter(|(key, backup)| {
+                        restore_key(&key.destination, backup.as_deref()).is_err()
+                    })
+                    .map(|(key, _)| key.destination.display().to_string())
+                    .collect::<Vec<_>>();
+
+                if unrestored.is_empty() {
+                    return Err(err.context("no key was written"));
+                }
+
+                return Err(err.context(format!(
+                    "failed to restore the previous keys at {}",
+                    unrestored.join(", ")
+                )));
+            }
+        }
+    }
+
+    for (key, backup) in committed {
+        sync_parent(&key.destination)?;
+
+        if let Some(backup) = backup {
+            std::fs::remove_file(backup)?;
+        }
+
+        managed.insert(key.destination.to_string_lossy().into_owned());
+    }
+
+    Ok(())
+}
+
+/// Removes managed keys that are not in the manifest, printing each one
+/// removed so wire can report it.
+fn remove_stale_keys(
+    manifest: &Manifest,
+    managed: &mut BTreeSet<String>,
+) -> Result<(), anyhow::Error> {
+    let stale = managed
+        .iter()
+        .filter(|destination| !manifest.destinations.contains(destination))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for destination in stale {
+        match std::fs::remove_file(&destination) {
+            Ok(()) => println!("{REMOVED_KEY_PREFIX}{destination}"),
+            // already removed by someone else
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        managed.remove(&destination);
+    }
+
+    Ok(())
+}
+
+/// Accepts wire's `Hello` if it speaks the same protocol version and only
+/// uses capabilities this agent supports.
+fn check_hello(hello: &Hello) -> Result<(), anyhow::Error> {
+    if hello.version != PROTOCOL_VERSION {
+        return Err(anyhow::anyhow!(
+            "wire speaks protocol version {}, but this agent speaks {PROTOCOL_VERSION}",
+            hello.version
+        ));
+    }
+
+    let unsupported = hello
+        .capabilities
+        .iter()
+        .filter(|capability| !CAPABILITIES.contains(&capability.as_str()))
+        .collect::<Vec<_>>();
+
+    if !unsupported.is_empty() {
+        return Err(anyhow::anyhow!(
+            "wire uses capabilities this agent does not support: {unsupported:?}"
+        ));
+    }
+
+    Ok(())
+}
+
 fn pretty_keyspec(spec: &KeySpec) -> String {
     format!(
         "{} {}:{} {}",
@@ -37,58 +412,214 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let mut framed = FramedRead::new(stdin, LengthDelimitedCodec::new());
 
-    while let Some(spec_bytes) = framed.next().await {
-        let spec_bytes = Bytes::from(BASE64_STANDARD.decode(spec_bytes?)?);
-        let spec = KeySpec::decode(spec_bytes)?;
+    let hello = format!(
+        "{HELLO_PREFIX}{}",
+        BASE64_STANDARD.encode(
+            Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES.iter().map(ToString::to_string).collect(),
+            }
+            .encode_to_vec()
+        )
+    );
+    println!("{hello}");
 
-        let key_bytes = BASE64_STANDARD.decode(
-            framed
-                .next()
-                .await
-                .expect("expected key_bytes to come after spec_bytes")?,
-        )?;
+    let wire_hello = next_frame(&mut framed, "a hello from wire")
+        .await
+        .and_then(|bytes| Ok(Hello::decode(Bytes::from(bytes))?))
+        .and_then(|wire_hello| check_hello(&wire_hello));
 
-        let digest = Sha256::digest(&key_bytes).to_vec();
+    if let Err(err) = wire_hello {
+        // wire only reads stderr when the agent failed
+        eprintln!("{hello}");
+        return Err(err);
+    }
 
-        println!(
-            "Writing {}, {:?} bytes of data",
-            pretty_keyspec(&spec),
-            key_bytes.len()
-        );
+    // inspecting manifests are answered until wire sends the one to apply
+    let manifest = loop {
+        let manifest_bytes = next_frame(&mut framed, "a manifest to come after the hello").await?;
+        let manifest = Manifest::decode(Bytes::from(manifest_bytes))?;
 
-        if digest != spec.digest {
-            return Err(anyhow::anyhow!(
-                "digest of {spec:?} did not match {digest:?}! Please create an issue!"
-            ));
+        if !manifest.inspect {
+            break manifest;
         }
 
-        let path = PathBuf::from(&spec.destination);
-        create_path(&path)?;
+        for destination in &manifest.destinations {
+            let state = inspect_key(destination)?;
 
-        let mut file = File::create(path).await?;
-        let mut permissions = file.metadata().await?.permissions();
-
-        permissions.set_mode(spec.permissions);
-        file.set_permissions(permissions).await?;
+            println!(
+                "{KEY_STATE_PREFIX}{}",
+                BASE64_STANDARD.encode(state.encode_to_vec())
+            );
+        }
+    };
 
-        let user = User::from_name(&spec.user)?;
-        let group = Group::from_name(&spec.group)?;
+    // wire ends a session that changes nothing with an empty manifest
+    if manifest.keys == 0 && !manifest.remove_stale {
+        return Ok(());
+    }
 
-        chown(
-            spec.destination,
-            // Default uid/gid to 0. This is then wrapped around an Option again for
-            // the function.
-            Some(user.map_or(0, |user| user.uid.into())),
-            Some(group.map_or(0, |group| group.gid.into())),
-        )?;
+    let managed_keys_file = managed_keys_file()?;
+    let mut managed = read_managed_keys(&managed_keys_file)?;
 
-        file.write_all(&key_bytes).await?;
+    let mut staged = Vec::new();
 
-        // last key, goobye
-        if spec.last {
-            break;
+    // the whole batch is rolled back if any key fails
+    if let Err(err) = receive_keys(&mut framed, &manifest, &mut staged).await {
+        for key in &staged {
+            let _ = std::fs::remove_file(&key.temporary);
         }
+
+        return Err(err);
     }
 
+    commit_keys(staged, &mut managed)?;
+
+    if manifest.remove_stale {
+        remove_stale_keys(&manifest, &mut managed)?;
+    }
+
+    write_managed_keys(&managed_keys_file, &managed)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(destination: &Path, contents: &str) -> StagedKey {
+        let temporary = temporary_path(destination);
+        std::fs::write(&temporary, contents).unwrap();
+
+        StagedKey {
+            temporary,
+            destination: destination.to_path_buf(),
+        }
+    }
+
+    fn files(directory: &Path) -> BTreeSet<String> {
+        std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_commit_keys() {
+        let directory = tempdir::TempDir::new("wire-key-agent").unwrap();
+        let existing = directory.path().join("existing");
+        let created = directory.path().join("created");
+
+        std::fs::write(&existing, "old").unwrap();
+
+        let mut managed = BTreeSet::new();
+        commit_keys(
+            vec![stage(&existing, "new"), stage(&created, "created")],
+            &mut managed,
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&created).unwrap(), "created");
+        assert_eq!(
+            files(directory.path()),
+            BTreeSet::from(["created".to_string(), "existing".to_string()])
+        );
+        assert_eq!(managed.len(), 2);
+    }
+
+    #[test]
+    fn test_commit_keys_rolls_back() {
+        let directory = tempdir::TempDir::new("wire-key-agent").unwrap();
+        let existing = directory.path().join("existing");
+        let created = directory.path().join("created");
+        let remaining = directory.path().join("remaining");
+
+        std::fs::write(&existing, "old").unwrap();
+
+        // the temporary file of `broken` was never written, so its rename fails
+        let broken = StagedKey {
+            temporary: temporary_path(&directory.path().join("broken")),
+            destination: directory.path().join("broken"),
+        };
+
+        let mut managed = BTreeSet::new();
+        let result = commit_keys(
+            vec![
+                stage(&existing, "new"),
+                stage(&created, "created"),
+                broken,
+                stage(&remaining, "remaining"),
+            ],
+            &mut managed,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "old");
+        assert_eq!(
+            files(directory.path()),
+            BTreeSet::from(["existing".to_string()])
+        );
+        assert!(managed.is_empty());
+    }
+
+    #[test]
+    fn test_remove_stale_keys_keeps_unmanaged() {
+        let directory = tempdir::TempDir::new("wire-key-agent").unwrap();
+        let existing = directory.path().join("existing");
+        let written = directory.path().join("written");
+        let temporary = temporary_path(&written);
+
+        std::fs::write(&existing, "existing").unwrap();
+        std::fs::write(&temporary, "written").unwrap();
+
+        // both keys are declared, but only `written` was uploaded
+        let mut managed = BTreeSet::new();
+        commit_keys(
+            vec![StagedKey {
+                temporary,
+                destination: written.clone(),
+            }],
+            &mut managed,
+        )
+        .unwrap();
+
+        assert_eq!(
+            managed,
+            BTreeSet::from([written.to_string_lossy().into_owned()])
+        );
+
+        // neither key is declared anymore
+        remove_stale_keys(
+            &Manifest {
+                remove_stale: true,
+                ..Default::default()
+            },
+            &mut managed,
+        )
+        .unwrap();
+
+        assert!(existing.exists());
+        assert!(!written.exists());
+        assert!(managed.is_empty());
+    }
+
+    #[test]
+    fn test_inspect_key() {
+        let directory = tempdir::TempDir::new("wire-key-agent").unwrap();
+        let key = directory.path().join("key");
+        let contents = vec![7u8; 3 * 8192 + 1];
+
+        std::fs::write(&key, &contents).unwrap();
+
+        let state = inspect_key(key.to_str().unwrap()).unwrap();
+
+        assert!(state.exists);
+        assert_eq!(state.digest, Sha256::digest(&contents).to_vec());
+
+        let missing = inspect_key(directory.path().join("missing").to_str().unwrap()).unwrap();
+
+        assert!(!missing.exists);
+    }
+}
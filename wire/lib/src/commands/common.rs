@@ -3,7 +3,8 @@
 
 use std::collections::HashMap;
 
-use tracing::instrument;
+use itertools::Itertools;
+use tracing::{instrument, warn};
 
 use crate::{
     EvalGoal, SubCommandModifiers,
@@ -11,7 +12,7 @@ use crate::{
     errors::{CommandError, HiveLibError},
     hive::{
         HiveLocation,
-        node::{Context, Push},
+        node::{Context, Name, Push, Target},
     },
 };
 
@@ -25,28 +26,39 @@ fn get_common_copy_path_help(error: &CommandError) -> Option<String> {
     }
 }
 
-pub async fn push(context: &Context<'_>, push: Push<'_>) -> Result<(), HiveLibError> {
+/// A `nix copy` of `path` from or to `remote`, with `NIX_SSHOPTS` set to the
+/// remote's SSH options.
+fn nix_copy(
+    direction: &str,
+    remote: &Target,
+    path: &str,
+    modifiers: SubCommandModifiers,
+) -> Result<(String, HashMap<String, String>), HiveLibError> {
     let command_string = format!(
         "nix --extra-experimental-features nix-command \
-        copy --substitute-on-destination --to ssh://{user}@{host} {path}",
-        user = context.node.target.user,
-        host = context.node.target.get_preferred_host()?,
-        path = match push {
-            Push::Derivation(drv) => format!("{drv} --derivation"),
-            Push::Path(path) => path.clone(),
-        }
+        copy --substitute-on-destination {direction} ssh://{user}@{host} {path}",
+        user = remote.user,
+        host = remote.get_preferred_host()?,
     );
 
-    let child = run_command_with_env(
-        &CommandArguments::new(command_string, context.modifiers)
-            .mode(crate::commands::ChildOutputMode::Nix),
+    Ok((
+        command_string,
         HashMap::from([(
             "NIX_SSHOPTS".into(),
-            context
-                .node
-                .target
-                .create_ssh_opts(context.modifiers, false)?,
+            remote.create_ssh_opts(modifiers, false)?,
         )]),
+    ))
+}
+
+async fn run_copy(
+    context: &Context<'_>,
+    (command_string, envs): (String, HashMap<String, String>),
+    path: &str,
+) -> Result<(), HiveLibError> {
+    let child = run_command_with_env(
+        &CommandArguments::new(command_string, context.modifiers)
+            .mode(crate::commands::ChildOutputMode::Nix),
+        envs,
     )
     .await?;
 
@@ -60,7 +72,7 @@ pub async fn push(context: &Context<'_>, push: Push<'_>) -> Result<(), HiveLibEr
 
     status.map_err(|error| HiveLibError::NixCopyError {
         name: context.name.clone(),
-        path: push.to_string(),
+        path: path.to_string(),
         error: Box::new(error),
         help,
     })?;
@@ -68,6 +80,81 @@ pub async fn push(context: &Context<'_>, push: Push<'_>) -> Result<(), HiveLibEr
     Ok(())
 }
 
+pub async fn push(
+    context: &Context<'_>,
+    target: &Target,
+    push: Push<'_>,
+) -> Result<(), HiveLibError> {
+    let path = match push {
+        Push::Derivation(drv) => format!("{drv} --derivation"),
+        Push::Path(path) => path.clone(),
+    };
+
+    run_copy(
+        context,
+        nix_copy("--to", target, &path, context.modifiers)?,
+        &push.to_string(),
+    )
+    .await
+}
+
+/// The `nix copy` run on the builder to copy a path straight to the node. The
+/// builder connects with the node's SSH options, and must not wait for a
+/// prompt.
+fn builder_copy_command(
+    target: &Target,
+    path: &str,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    Ok(format!(
+        "NIX_SSHOPTS='{ssh_opts} -o BatchMode=yes' \
+        nix --extra-experimental-features nix-command \
+        copy --substitute-on-destination --to ssh://{user}@{host} {path}",
+        ssh_opts = target.create_ssh_opts(modifiers, false)?,
+        user = target.user,
+        host = target.get_preferred_host()?,
+    ))
+}
+
+/// Copies a path from the node's builder straight to the node. If the builder
+/// cannot reach the node, the path is copied to this machine over the
+/// builder's SSH options, then on to the node over the node's.
+pub async fn copy_from_builder(
+    context: &Context<'_>,
+    builder: &Target,
+    path: &str,
+) -> Result<(), HiveLibError> {
+    let direct = run_command(
+        &CommandArguments::new(
+            builder_copy_command(&context.node.target, path, context.modifiers)?,
+            context.modifiers,
+        )
+        .on_target(Some(builder))
+        .mode(crate::commands::ChildOutputMode::Nix),
+    )
+    .await?
+    .wait_till_success()
+    .await;
+
+    let Err(err) = direct else {
+        return Ok(());
+    };
+
+    warn!(
+        "Failed to copy {path} from the builder to {} directly, copying it through this machine: {err}",
+        context.name
+    );
+
+    run_copy(
+        context,
+        nix_copy("--from", builder, path, context.modifiers)?,
+        path,
+    )
+    .await?;
+
+    push(context, &context.node.target, Push::Path(&path.to_string())).await
+}
+
 fn get_common_command_help(error: &CommandError) -> Option<String> {
     if let CommandError::CommandFailed { logs, .. } = error
         // marshmallow: your using this repo as a hive you idiot
@@ -83,6 +170,20 @@ fn get_common_command_help(error: &CommandError) -> Option<String> {
     }
 }
 
+/// Quotes a value for a POSIX shell, so it is passed as a single argument.
+#[must_use]
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// A Nix list of node names, to be placed within double quotes.
+fn name_list(names: &[Name]) -> String {
+    format!(
+        "[ {} ]",
+        names.iter().map(|name| format!("\\\"{name}\\\"")).join(" ")
+    )
+}
+
 /// Evaluates the hive in flakeref with regards to the given goal,
 /// and returns stdout.
 #[instrument(ret(level = tracing::Level::TRACE), skip_all)]
@@ -98,6 +199,8 @@ pub async fn evaluate_hive_attribute(
                 match goal {
                     EvalGoal::Inspect => "hive.inspect".to_string(),
                     EvalGoal::GetTopLevel(node) => format!("hive.topLevels.{node}"),
+                    EvalGoal::GetTopLevels(nodes) =>
+                        format!("hive.topLevelsOf {}", name_list(nodes)),
                 }
             )
         }
@@ -108,6 +211,8 @@ pub async fn evaluate_hive_attribute(
                 match goal {
                     EvalGoal::Inspect => "inspect".to_string(),
                     EvalGoal::GetTopLevel(node) => format!("topLevels.{node}"),
+                    EvalGoal::GetTopLevels(nodes) =>
+                        format!("topLevelsOf --apply \"f: f {}\"", name_list(nodes)),
                 }
             )
         }
@@ -148,3 +253,55 @@ pub async fn evaluate_hive_attribute(
             Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("nginx.service"), "'nginx.service'");
+        assert_eq!(shell_quote("a=1&b=2"), "'a=1&b=2'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_copy_ssh_options() {
+        let modifiers = SubCommandModifiers::default();
+        let mut target = Target::from_host("node");
+        target.ssh_options = vec!["-o".into(), "ProxyJump=bastion".into()];
+        target.port = 2222;
+
+        let mut builder = Target::from_host("builder");
+        builder.ssh_options = vec!["-i".into(), "/etc/builder-key".into()];
+
+        // the builder connects to the node with the node's options
+        let direct = builder_copy_command(&target, "/nix/store/a", modifiers).unwrap();
+
+        assert!(direct.starts_with(&format!(
+            "NIX_SSHOPTS='{} -o BatchMode=yes'",
+            target.create_ssh_opts(modifiers, false).unwrap()
+        )));
+        assert!(direct.contains("-o ProxyJump=bastion"));
+        assert!(!direct.contains("/etc/builder-key"));
+        assert!(direct.ends_with("--to ssh://root@node /nix/store/a"));
+
+        // copying through this machine uses each side's own options
+        let (fetch, envs) = nix_copy("--from", &builder, "/nix/store/a", modifiers).unwrap();
+
+        assert!(fetch.ends_with("--from ssh://root@builder /nix/store/a"));
+        assert_eq!(
+            envs.get("NIX_SSHOPTS"),
+            Some(&builder.create_ssh_opts(modifiers, false).unwrap())
+        );
+
+        let (push, envs) = nix_copy("--to", &target, "/nix/store/a", modifiers).unwrap();
+
+        assert!(push.ends_with("--to ssh://root@node /nix/store/a"));
+        assert_eq!(
+            envs.get("NIX_SSHOPTS"),
+            Some(&target.create_ssh_opts(modifiers, false).unwrap())
+        );
+    }
+}
@@ -9,8 +9,14 @@ use gjson::Value;
 use itertools::Itertools;
 use nix_compat::log::{AT_NIX_PREFIX, VerbosityLevel};
 use num_enum::TryFromPrimitive;
+use tokio::sync::mpsc;
 use tracing::{debug, error, info, trace, warn};
 
+#[cfg(feature = "native-ssh")]
+use crate::{
+    SshBackend,
+    commands::native::{NativeChildChip, native_command},
+};
 use crate::{
     SubCommandModifiers,
     commands::noninteractive::{NonInteractiveChildChip, non_interactive_command_with_env},
@@ -19,6 +25,8 @@ use crate::{
 };
 
 pub(crate) mod common;
+#[cfg(feature = "native-ssh")]
+pub(crate) mod native;
 pub(crate) mod noninteractive;
 pub(crate) mod pty;
 
@@ -35,6 +43,13 @@ pub enum Either<L, R> {
     Right(R),
 }
 
+pub(crate) enum ChildChip {
+    Interactive(InteractiveChildChip),
+    NonInteractive(NonInteractiveChildChip),
+    #[cfg(feature = "native-ssh")]
+    Native(NativeChildChip),
+}
+
 #[derive(Debug)]
 pub(crate) struct CommandArguments<'t, S: AsRef<str>> {
     modifiers: SubCommandModifiers,
@@ -96,29 +111,49 @@ impl<'a, S: AsRef<str>> CommandArguments<'a, S> {
         self.log_stdout = true;
         self
     }
+
+    /// Commands wire converses with over stdin also have their stdout lines
+    /// sent as they arrive, see `WireCommandChip::next_stdout_line`.
+    fn stdout_lines(
+        &self,
+    ) -> (
+        Option<mpsc::UnboundedSender<String>>,
+        Option<mpsc::UnboundedReceiver<String>>,
+    ) {
+        self.keep_stdin_open.then(mpsc::unbounded_channel).unzip()
+    }
 }
 
 pub(crate) async fn run_command<S: AsRef<str>>(
     arguments: &CommandArguments<'_, S>,
-) -> Result<Either<InteractiveChildChip, NonInteractiveChildChip>, HiveLibError> {
+) -> Result<ChildChip, HiveLibError> {
     run_command_with_env(arguments, HashMap::new()).await
 }
 
 pub(crate) async fn run_command_with_env<S: AsRef<str>>(
     arguments: &CommandArguments<'_, S>,
     envs: HashMap<String, String>,
-) -> Result<Either<InteractiveChildChip, NonInteractiveChildChip>, HiveLibError> {
+) -> Result<ChildChip, HiveLibError> {
+    // remote commands run over the in-process session when selected.
+    // `envs` only ever applies to the local process, as with `ssh`
+    #[cfg(feature = "native-ssh")]
+    if let Some(target) = arguments.target
+        && matches!(arguments.modifiers.ssh_backend, SshBackend::Native)
+    {
+        return Ok(ChildChip::Native(native_command(arguments, target).await?));
+    }
+
     // use the non interactive command runner when forced
     // ... or when there is no reason for interactivity, local and unprivileged
     if arguments.modifiers.non_interactive
         || (arguments.target.is_none() && !arguments.is_elevated())
     {
-        return Ok(Either::Right(non_interactive_command_with_env(
+        return Ok(ChildChip::NonInteractive(non_interactive_command_with_env(
             arguments, envs,
         )?));
     }
 
-    Ok(Either::Left(
+    Ok(ChildChip::Interactive(
         interactive_command_with_env(arguments, envs).await?,
     ))
 }
@@ -128,28 +163,67 @@ pub(crate) trait WireCommandChip {
 
     async fn wait_till_success(self) -> Result<Self::ExitStatus, CommandError>;
     async fn write_stdin(&mut self, data: Vec<u8>) -> Result<(), HiveLibError>;
+
+    /// Waits for the next line the command prints to stdout. Only commands
+    /// that keep stdin open are read incrementally, `None` is returned once
+    /// stdout is closed or for any other command.
+    async fn next_stdout_line(&mut self) -> Option<String>;
 }
 
 type ExitStatus = Either<(portable_pty::ExitStatus, String), (std::process::ExitStatus, String)>;
 
-impl WireCommandChip for Either<InteractiveChildChip, NonInteractiveChildChip> {
+impl WireCommandChip for ChildChip {
     type ExitStatus = ExitStatus;
 
     async fn write_stdin(&mut self, data: Vec<u8>) -> Result<(), HiveLibError> {
         match self {
-            Self::Left(left) => left.write_stdin(data).await,
-            Self::Right(right) => right.write_stdin(data).await,
+            Self::Interactive(child) => child.write_stdin(data).await,
+            Self::NonInteractive(child) => child.write_stdin(data).await,
+            #[cfg(feature = "native-ssh")]
+            Self::Native(child) => child.write_stdin(data).await,
+        }
+    }
+
+    async fn next_stdout_line(&mut self) -> Option<String> {
+        match self {
+            Self::Interactive(child) => child.next_stdout_line().await,
+            Self::NonInteractive(child) => child.next_stdout_line().await,
+            #[cfg(feature = "native-ssh")]
+            Self::Native(child) => child.next_stdout_line().await,
         }
     }
 
     async fn wait_till_success(self) -> Result<Self::ExitStatus, CommandError> {
         match self {
-            Self::Left(left) => left.wait_till_success().await.map(Either::Left),
-            Self::Right(right) => right.wait_till_success().await.map(Either::Right),
+            Self::Interactive(child) => child.wait_till_success().await.map(Either::Left),
+            Self::NonInteractive(child) => child.wait_till_success().await.map(Either::Right),
+            #[cfg(feature = "native-ssh")]
+            Self::Native(child) => child.wait_till_success().await.map(Either::Right),
         }
     }
 }
 
+/// The target of events traced from nix's logs, which `--output json` emits
+/// as nix events.
+pub const NIX_TARGET: &str = "nix";
+
+/// The `type` of a nix `result` log holding the progress of an activity, with
+/// the done, expected, running and failed counts as its fields.
+const RESULT_PROGRESS: u64 = 105;
+
+fn trace_gjson_progress(log: &Value<'_>) {
+    let fields = log.get("fields");
+
+    debug!(
+        target: NIX_TARGET,
+        activity = log.get("id").u64(),
+        done = fields.get("0").u64(),
+        expected = fields.get("1").u64(),
+        running = fields.get("2").u64(),
+        failed = fields.get("3").u64(),
+    );
+}
+
 fn trace_gjson_str<'a>(log: &'a Value<'a>, msg: &'a str) -> Option<String> {
     if msg.is_empty() {
         return None;
@@ -172,12 +246,12 @@ fn trace_gjson_str<'a>(log: &'a Value<'a>, msg: &'a str) -> Option<String> {
     let msg = strip_ansi_escapes::strip_str(msg);
 
     match level {
-        VerbosityLevel::Info => info!("{msg}"),
-        VerbosityLevel::Warn | VerbosityLevel::Notice => warn!("{msg}"),
-        VerbosityLevel::Error => error!("{msg}"),
-        VerbosityLevel::Debug => debug!("{msg}"),
+        VerbosityLevel::Info => info!(target: NIX_TARGET, "{msg}"),
+        VerbosityLevel::Warn | VerbosityLevel::Notice => warn!(target: NIX_TARGET, "{msg}"),
+        VerbosityLevel::Error => error!(target: NIX_TARGET, "{msg}"),
+        VerbosityLevel::Debug => debug!(target: NIX_TARGET, "{msg}"),
         VerbosityLevel::Vomit | VerbosityLevel::Talkative | VerbosityLevel::Chatty => {
-            trace!("{msg}");
+            trace!(target: NIX_TARGET, "{msg}");
         }
     }
 
@@ -234,6 +308,10 @@ impl ChildOutputMode {
             return trace_gjson_str(&log, text.str());
         }
 
+        if log.get("action").str() == "result" && log.get("type").u64() == RESULT_PROGRESS {
+            trace_gjson_progress(&log);
+        }
+
         None
     }
 }
@@ -0,0 +1,679 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+//! In-process SSH transport built on `russh`.
+//!
+//! Each target gets a single session that is kept alive for the whole run,
+//! and every command opens its own channel on it. Exit codes are read from
+//! the channel, so no sentinels need to be parsed out of the output.
+//!
+//! Like `--non-interactive`, there is no PTY, so privilege escalation must
+//! not prompt for a password.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::ExitStatus,
+    sync::{Arc, LazyLock, nonpoison},
+    time::Duration,
+};
+
+use itertools::Itertools;
+use russh::{
+    ChannelMsg, Disconnect,
+    client::{self, Handle, Msg},
+    keys::{
+        PrivateKeyWithHashAlg, PublicKey, agent::client::AgentClient, check_known_hosts,
+        known_hosts::learn_known_hosts, load_secret_key,
+    },
+};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    sync::{Mutex, mpsc},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{Instrument, debug, error, instrument, trace, warn};
+
+use crate::{
+    StrictHostKeyChecking, SubCommandModifiers,
+    commands::{
+        CommandArguments, WireCommandChip,
+        noninteractive::{create_command_string, handle_io},
+    },
+    errors::{CommandError, HiveLibError},
+    hive::node::Target,
+};
+
+/// Interval between keepalive requests on idle sessions.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Size of the buffer between a channel and its log handlers.
+const CHANNEL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keys tried after the agent's identities, same as `ssh`'s defaults.
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+type Session = Arc<Handle<Client>>;
+type SessionKey = (Arc<str>, Arc<str>, u32);
+/// Connecting to a target holds its slot, so concurrent commands share one
+/// session.
+type SessionSlot = Arc<Mutex<Option<Session>>>;
+
+static SESSIONS: LazyLock<nonpoison::Mutex<HashMap<SessionKey, SessionSlot>>> =
+    LazyLock::new(|| nonpoison::Mutex::new(HashMap::new()));
+
+struct Client {
+    host: Arc<str>,
+    port: u16,
+    host_key_checking: StrictHostKeyChecking,
+}
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        if matches!(self.host_key_checking, StrictHostKeyChecking::No) {
+            return Ok(true);
+        }
+
+        match check_known_hosts(&self.host, self.port, server_public_key) {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                warn!(
+                    "Permanently adding {} to the list of known hosts",
+                    self.host
+                );
+                learn_known_hosts(&self.host, self.port, server_public_key)?;
+
+                Ok(true)
+            }
+            Err(err) => {
+                error!("Host key verification failed for {}: {err}", self.host);
+
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum RemoteExit {
+    Code(u32),
+    Signal(String),
+    Unknown,
+}
+
+pub(crate) struct NativeChildChip {
+    error_collection: Arc<Mutex<VecDeque<String>>>,
+    stdout_collection: Arc<Mutex<VecDeque<String>>>,
+    joinset: JoinSet<()>,
+    channel_handle: JoinHandle<Result<RemoteExit, CommandError>>,
+    original_command: String,
+    stdin: mpsc::UnboundedSender<Vec<u8>>,
+    stdout_lines: Option<mpsc::UnboundedReceiver<String>>,
+}
+
+/// Identity files passed with `-i` or `-o IdentityFile=` in
+/// `deployment.target.sshOptions`, followed by the default keys in `~/.ssh`.
+/// Any other option is rejected rather than silently ignored.
+fn identity_files(
+    ssh_options: &[String],
+    home: Option<&str>,
+) -> Result<Vec<PathBuf>, CommandError> {
+    let expand = |path: &str| match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    };
+    let unsupported = |option: &str| CommandError::NativeSshUnsupportedOption(option.to_string());
+
+    let mut files = Vec::new();
+    let mut options = ssh_options.iter().map(String::as_str);
+
+    while let Some(option) = options.next() {
+        let (flag, value) = match option {
+            "-i" | "-o" => (option, options.next().ok_or_else(|| unsupported(option))?),
+            _ if option.starts_with("-i") || option.starts_with("-o") => option.split_at(2),
+            _ => return Err(unsupported(option)),
+        };
+
+        let path = if flag == "-o" {
+            value
+                .split_once(['=', ' '])
+                .filter(|(key, _)| key.eq_ignore_ascii_case("IdentityFile"))
+                .map(|(_, path)| path)
+                .ok_or_else(|| unsupported(value))?
+        } else {
+            value
+        };
+
+        files.push(expand(path.trim()));
+    }
+
+    if let Some(home) = home {
+        files.extend(
+            DEFAULT_IDENTITIES
+                .iter()
+                .map(|name| PathBuf::from(home).join(".ssh").join(name)),
+        );
+    }
+
+    Ok(files)
+}
+
+async fn authenticate(
+    handle: &mut Handle<Client>,
+    target: &Target,
+    host: &Arc<str>,
+    identity_files: Vec<PathBuf>,
+) -> Result<(), HiveLibError> {
+    let user = target.user.to_string();
+    let hash_alg = handle
+        .best_supported_rsa_hash()
+        .await
+        .ok()
+        .flatten()
+        .flatten();
+
+    if let Ok(mut agent) = AgentClient::connect_env().await {
+        for key in agent.request_identities().await.unwrap_or_default() {
+            match handle
+                .authenticate_publickey_with(&user, key, hash_alg, &mut agent)
+                .await
+            {
+                Ok(result) if result.success() => return Ok(()),
+                Ok(_) => {}
+                Err(err) => debug!("ssh-agent failed to sign: {err}"),
+            }
+        }
+    }
+
+    for path in identity_files {
+        let key = match load_secret_key(&path, None) {
+            Ok(key) => key,
+            Err(err) => {
+                trace!("skipping identity {}: {err}", path.display());
+                continue;
+            }
+        };
+
+        let result = handle
+            .authenticate_publickey(&user, PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg))
+            .await
+            .map_err(|source| {
+                HiveLibError::CommandError(CommandError::NativeSshConnect {
+                    host: host.to_string(),
+                    source: Box::new(source),
+                })
+            })?;
+
+        if result.success() {
+            return Ok(());
+        }
+    }
+
+    Err(HiveLibError::CommandError(CommandError::NativeSshAuth {
+        user,
+        host: host.to_string(),
+    }))
+}
+
+async fn connect(
+    target: &Target,
+    host: &Arc<str>,
+    modifiers: SubCommandModifiers,
+) -> Result<Handle<Client>, HiveLibError> {
+    let connect_error = |source| {
+        HiveLibError::CommandError(CommandError::NativeSshConnect {
+            host: host.to_string(),
+            source: Box::new(source),
+        })
+    };
+
+    let port = u16::try_from(target.port).map_err(|_| {
+        connect_error(russh::Error::IO(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("port {} is out of range", target.port),
+        )))
+    })?;
+
+    // checked before connecting, instead of ignoring options like ProxyJump
+    let identity_files = identity_files(&target.ssh_options, std::env::var("HOME").ok().as_deref())
+        .map_err(HiveLibError::CommandError)?;
+
+    let config = client::Config {
+        keepalive_interval: Some(KEEPALIVE_INTERVAL),
+        inactivity_timeout: None,
+        ..Default::default()
+    };
+
+    let client = Client {
+        host: host.clone(),
+        port,
+        host_key_checking: modifiers.ssh_accept_host,
+    };
+
+    debug!(
+        "opening a native ssh session to {}@{host}:{port}",
+        target.user
+    );
+
+    let mut handle = client::connect(Arc::new(config), (host.as_ref(), port), client)
+        .await
+        .map_err(connect_error)?;
+
+    authenticate(&mut handle, target, host, identity_files).await?;
+
+    Ok(handle)
+}
+
+/// Returns the open session to the target's preferred host, connecting if
+/// there is none yet or the previous one was closed.
+async fn get_session(
+    target: &Target,
+    modifiers: SubCommandModifiers,
+) -> Result<Session, HiveLibError> {
+    let host = target.get_preferred_host()?;
+    let slot = SESSIONS
+        .lock()
+        .entry((target.user.clone(), host.clone(), target.port))
+        .or_default()
+        .clone();
+
+    let mut slot = slot.lock().await;
+
+    if let Some(session) = slot.as_ref()
+        && !session.is_closed()
+    {
+        return Ok(session.clone());
+    }
+
+    let session = Arc::new(connect(target, host, modifiers).await?);
+    *slot = Some(session.clone());
+
+    Ok(session)
+}
+
+/// Closes the session to the target's preferred host, so the next command
+/// has to authenticate from scratch.
+pub(crate) async fn disconnect(target: &Target) -> Result<(), HiveLibError> {
+    let host = target.get_preferred_host()?;
+    let slot = SESSIONS
+        .lock()
+        .remove(&(target.user.clone(), host.clone(), target.port));
+
+    let Some(slot) = slot else {
+        return Ok(());
+    };
+
+    if let Some(session) = slot.lock().await.take()
+        && let Err(err) = session
+            .disconnect(Disconnect::ByApplication, "", "en")
+            .await
+    {
+        debug!("failed to disconnect native ssh session: {err}");
+    }
+
+    Ok(())
+}
+
+async fn drive_channel(
+    mut channel: russh::Channel<Msg>,
+    mut stdin: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut stdout: DuplexStream,
+    mut stderr: DuplexStream,
+) -> Result<RemoteExit, CommandError> {
+    let mut exit = RemoteExit::Unknown;
+    let mut stdin_open = true;
+
+    loop {
+        tokio::select! {
+            data = stdin.recv(), if stdin_open => {
+                if let Some(data) = data {
+                    channel.data(&data[..]).await.map_err(|x| CommandError::NativeSshChannel(Box::new(x)))?;
+                } else {
+                    stdin_open = false;
+                    channel.eof().await.map_err(|x| CommandError::NativeSshChannel(Box::new(x)))?;
+                }
+            }
+            message = channel.wait() => match message {
+                // the log handlers may have gone away, which is not our concern
+                Some(ChannelMsg::Data { data }) => {
+                    let _ = stdout.write_all(&data).await;
+                }
+                Some(ChannelMsg::ExtendedData { data, ext: 1 }) => {
+                    let _ = stderr.write_all(&data).await;
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => {
+                    exit = RemoteExit::Code(exit_status);
+                }
+                Some(ChannelMsg::ExitSignal { signal_name, .. }) => {
+                    exit = RemoteExit::Signal(format!("{signal_name:?}"));
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+
+    Ok(exit)
+}
+
+#[instrument(skip_all, name = "run-native", fields(elevated = %arguments.is_elevated()))]
+pub(crate) async fn native_command<S: AsRef<str>>(
+    arguments: &CommandArguments<'_, S>,
+    target: &Target,
+) -> Result<NativeChildChip, HiveLibError> {
+    let session = get_session(target, arguments.modifiers).await?;
+    let command_string = create_command_string(arguments);
+
+    debug!("{command_string}");
+
+    let channel = session
+        .channel_open_session()
+        .await
+        .map_err(|x| HiveLibError::CommandError(CommandError::NativeSshChannel(Box::new(x))))?;
+
+    channel
+        .exec(true, command_string)
+        .await
+        .map_err(|x| HiveLibError::CommandError(CommandError::NativeSshChannel(Box::new(x))))?;
+
+    let (stdout_writer, stdout_reader) = tokio::io::duplex(CHANNEL_BUFFER_SIZE);
+    let (stderr_writer, stderr_reader) = tokio::io::duplex(CHANNEL_BUFFER_SIZE);
+    let (stdin, stdin_receiver) = mpsc::unbounded_channel();
+
+    let error_collection = Arc::new(Mutex::new(VecDeque::<String>::with_capacity(10)));
+    let stdout_collection = Arc::new(Mutex::new(VecDeque::<String>::with_capacity(10)));
+
+    let mut joinset = JoinSet::new();
+    let output_mode = Arc::new(arguments.output_mode);
+    let (stdout_sender, stdout_lines) = arguments.stdout_lines();
+
+    joinset.spawn(
+        handle_io(
+            stderr_reader,
+            output_mode.clone(),
+            error_collection.clone(),
+            None,
+            true,
+            true,
+        )
+        .in_current_span(),
+    );
+    joinset.spawn(
+        handle_io(
+            stdout_reader,
+            output_mode.clone(),
+            stdout_collection.clone(),
+            stdout_sender,
+            false,
+            arguments.log_stdout,
+        )
+        .in_current_span(),
+    );
+
+    let channel_handle = tokio::spawn(
+        drive_channel(channel, stdin_receiver, stdout_writer, stderr_writer).in_current_span(),
+    );
+
+    Ok(NativeChildChip {
+        error_collection,
+        stdout_collection,
+        joinset,
+        channel_handle,
+        original_command: arguments.command_string.as_ref().to_string(),
+        stdin,
+        stdout_lines,
+    })
+}
+
+impl WireCommandChip for NativeChildChip {
+    type ExitStatus = (ExitStatus, String);
+
+    async fn wait_till_success(self) -> Result<Self::ExitStatus, CommandError> {
+        // closing stdin sends EOF to the remote command
+        drop(self.stdin);
+
+        let exit = self
+            .channel_handle
+            .await
+            .map_err(CommandError::JoinError)??;
+        let _ = self.joinset.join_all().await;
+
+        debug!("remote exit: {exit:?}");
+
+        let (code, reason) = match exit {
+            RemoteExit::Code(0) => {
+                let stdout = self.stdout_collection.lock().await.iter().rev().join("\n");
+
+                return Ok((ExitStatus::from_raw(0), stdout));
+            }
+            RemoteExit::Code(code) => (format!("code {code}"), "known-status"),
+            RemoteExit::Signal(signal) => (format!("signal {signal}"), "known-status"),
+            RemoteExit::Unknown => ("no exit code".to_string(), "channel-closed-without-status"),
+        };
+
+        let logs = self.error_collection.lock().await.iter().rev().join("\n");
+
+        Err(CommandError::CommandFailed {
+            command_ran: self.original_command,
+            logs,
+            code,
+            reason,
+        })
+    }
+
+    async fn write_stdin(&mut self, data: Vec<u8>) -> Result<(), HiveLibError> {
+        trace!("Writing {} bytes", data.len());
+
+        self.stdin
+            .send(data)
+            .map_err(|_| HiveLibError::CommandError(CommandError::NativeSshChannelClosed))
+    }
+
+    async fn next_stdout_line(&mut self) -> Option<String> {
+        self.stdout_lines.as_mut()?.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env, fs,
+        net::{TcpListener, TcpStream},
+        process::{Child, Command},
+        thread::sleep,
+    };
+    use tempdir::TempDir;
+
+    /// A throwaway `sshd` on localhost, accepting `identity` for the current
+    /// user.
+    struct Sshd {
+        directory: TempDir,
+        child: Child,
+        port: u16,
+    }
+
+    impl Sshd {
+        fn start() -> Self {
+            let directory = TempDir::new("wire-sshd").unwrap();
+            let path = |name: &str| directory.path().join(name);
+
+            for key in ["host", "identity"] {
+                let status = Command::new("ssh-keygen")
+                    .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                    .arg(path(key))
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+            }
+
+            fs::copy(path("identity.pub"), path("authorized_keys")).unwrap();
+
+            // sshd refuses to run unless started with an absolute path
+            let sshd = env::var_os("PATH")
+                .into_iter()
+                .flat_map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+                .chain(["/usr/sbin".into(), "/usr/local/sbin".into()])
+                .map(|directory| directory.join("sshd"))
+                .find(|sshd| sshd.is_file())
+                .expect("sshd is not installed");
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+
+            let child = Command::new(sshd)
+                .args(["-D", "-e", "-f", "/dev/null", "-p", &port.to_string(), "-h"])
+                .arg(path("host"))
+                .arg("-o")
+                .arg(format!(
+                    "AuthorizedKeysFile={}",
+                    path("authorized_keys").display()
+                ))
+                .args([
+                    "-o",
+                    "ListenAddress=127.0.0.1",
+                    "-o",
+                    "StrictModes=no",
+                    "-o",
+                    "PidFile=none",
+                ])
+                .spawn()
+                .unwrap();
+
+            for _ in 0..50 {
+                if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                    break;
+                }
+
+                sleep(Duration::from_millis(100));
+            }
+
+            Self {
+                directory,
+                child,
+                port,
+            }
+        }
+
+        fn target(&self) -> Target {
+            let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+                .unwrap()
+                .unwrap();
+            let mut target = Target::from_host("127.0.0.1");
+
+            target.user = user.name.into();
+            target.port = self.port.into();
+            target.ssh_options = vec![
+                "-i".to_string(),
+                self.directory.path().join("identity").display().to_string(),
+            ];
+
+            target
+        }
+    }
+
+    impl Drop for Sshd {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "no_sshd_tests", ignore)]
+    async fn test_native_command() {
+        let sshd = Sshd::start();
+        let target = sshd.target();
+        let modifiers = SubCommandModifiers {
+            ssh_accept_host: StrictHostKeyChecking::No,
+            ..Default::default()
+        };
+        let run = async |command: &str| {
+            native_command(
+                &CommandArguments::new(command, modifiers).on_target(Some(&target)),
+                &target,
+            )
+            .await
+            .unwrap()
+            .wait_till_success()
+            .await
+        };
+
+        let (status, stdout) = run("echo hello").await.unwrap();
+        assert!(status.success());
+        assert_eq!(stdout, "hello");
+
+        assert!(matches!(
+            run("echo oops >&2; exit 3").await,
+            Err(CommandError::CommandFailed { code, logs, .. })
+                if code == "code 3" && logs.contains("oops")
+        ));
+
+        // stdin is forwarded, and closed once the command is awaited
+        let mut child = native_command(
+            &CommandArguments::new("cat", modifiers)
+                .on_target(Some(&target))
+                .keep_stdin_open(),
+            &target,
+        )
+        .await
+        .unwrap();
+        child.write_stdin(b"ping\n".to_vec()).await.unwrap();
+        assert_eq!(child.next_stdout_line().await.as_deref(), Some("ping"));
+        assert!(child.wait_till_success().await.is_ok());
+
+        // every command ran over the same session
+        assert_eq!(SESSIONS.lock().len(), 1);
+        disconnect(&target).await.unwrap();
+        assert!(SESSIONS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_identity_files() {
+        let options = [
+            "-i",
+            "~/.ssh/deploy",
+            "-o",
+            "IdentityFile=/etc/wire/key",
+            "-oIdentityFile=/etc/wire/other",
+        ]
+        .map(String::from);
+
+        assert_eq!(
+            identity_files(&options, Some("/home/user")).unwrap(),
+            vec![
+                PathBuf::from("/home/user/.ssh/deploy"),
+                PathBuf::from("/etc/wire/key"),
+                PathBuf::from("/etc/wire/other"),
+                PathBuf::from("/home/user/.ssh/id_ed25519"),
+                PathBuf::from("/home/user/.ssh/id_ecdsa"),
+                PathBuf::from("/home/user/.ssh/id_rsa"),
+            ]
+        );
+
+        assert_eq!(identity_files(&[], None).unwrap(), Vec::<PathBuf>::new());
+
+        for options in [
+            &["-o", "ProxyJump=bastion"][..],
+            &["-oProxyJump=bastion"],
+            &["-v"],
+            &["-i"],
+        ] {
+            assert!(matches!(
+                identity_files(
+                    &options.iter().map(|x| (*x).to_string()).collect::<Vec<_>>(),
+                    None
+                ),
+                Err(CommandError::NativeSshUnsupportedOption(..))
+            ));
+        }
+    }
+}
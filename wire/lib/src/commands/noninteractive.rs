@@ -17,7 +17,7 @@ use itertools::Itertools;
 use tokio::{
     io::{AsyncWriteExt, BufReader},
     process::{Child, ChildStdin, Command},
-    sync::Mutex,
+    sync::{Mutex, mpsc},
     task::JoinSet,
 };
 use tracing::{Instrument, debug, instrument, trace};
@@ -29,6 +29,7 @@ pub(crate) struct NonInteractiveChildChip {
     joinset: JoinSet<()>,
     original_command: String,
     stdin: ChildStdin,
+    stdout_lines: Option<mpsc::UnboundedReceiver<String>>,
 }
 
 #[instrument(skip_all, name = "run", fields(elevated = %arguments.is_elevated()))]
@@ -46,20 +47,7 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
         command
     };
 
-    let command_string = format!(
-        "{command_string}{extra}",
-        command_string = arguments.command_string.as_ref(),
-        extra = match arguments.output_mode {
-            ChildOutputMode::Generic | ChildOutputMode::Interactive => "",
-            ChildOutputMode::Nix => " --log-format internal-json",
-        }
-    );
-
-    let command_string = if let Some(escalation_command) = &arguments.privilege_escalation_command {
-        format!("{escalation_command} sh -c '{command_string}'")
-    } else {
-        command_string
-    };
+    let command_string = create_command_string(arguments);
 
     debug!("{command_string}");
 
@@ -87,12 +75,14 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
 
     let mut joinset = JoinSet::new();
     let output_mode = Arc::new(arguments.output_mode);
+    let (stdout_sender, stdout_lines) = arguments.stdout_lines();
 
     joinset.spawn(
         handle_io(
             stderr_handle,
             output_mode.clone(),
             error_collection.clone(),
+            None,
             true,
             true,
         )
@@ -103,6 +93,7 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
             stdout_handle,
             output_mode.clone(),
             stdout_collection.clone(),
+            stdout_sender,
             false,
             arguments.log_stdout,
         )
@@ -116,9 +107,29 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
         joinset,
         original_command: arguments.command_string.as_ref().to_string(),
         stdin,
+        stdout_lines,
     })
 }
 
+/// Appends the output mode's flags and wraps the command in the privilege
+/// escalation command, if any.
+pub(crate) fn create_command_string<S: AsRef<str>>(arguments: &CommandArguments<S>) -> String {
+    let command_string = format!(
+        "{command_string}{extra}",
+        command_string = arguments.command_string.as_ref(),
+        extra = match arguments.output_mode {
+            ChildOutputMode::Generic | ChildOutputMode::Interactive => "",
+            ChildOutputMode::Nix => " --log-format internal-json",
+        }
+    );
+
+    if let Some(escalation_command) = &arguments.privilege_escalation_command {
+        format!("{escalation_command} sh -c '{command_string}'")
+    } else {
+        command_string
+    }
+}
+
 impl WireCommandChip for NonInteractiveChildChip {
     type ExitStatus = (ExitStatus, String);
 
@@ -150,6 +161,10 @@ impl WireCommandChip for NonInteractiveChildChip {
         self.stdin.write_all(&data).await.unwrap();
         Ok(())
     }
+
+    async fn next_stdout_line(&mut self) -> Option<String> {
+        self.stdout_lines.as_mut()?.recv().await
+    }
 }
 
 #[instrument(skip_all, name = "log")]
@@ -157,6 +172,7 @@ pub async fn handle_io<R>(
     reader: R,
     output_mode: Arc<ChildOutputMode>,
     collection: Arc<Mutex<VecDeque<String>>>,
+    lines: Option<mpsc::UnboundedSender<String>>,
     is_error: bool,
     should_log: bool,
 ) where
@@ -174,8 +190,15 @@ pub async fn handle_io<R>(
         };
 
         if !is_error {
+            let line = String::from_utf8_lossy(&line).to_string();
+
+            if let Some(lines) = &lines {
+                // the receiver may have stopped reading
+                let _ = lines.send(line.clone());
+            }
+
             let mut queue = collection.lock().await;
-            queue.push_front(String::from_utf8_lossy(&line).to_string());
+            queue.push_front(line);
         } else if let Some(error_msg) = log.flatten() {
             let mut queue = collection.lock().await;
             queue.push_front(error_msg);
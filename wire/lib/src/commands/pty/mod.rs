@@ -18,7 +18,7 @@ use std::{
     os::fd::{AsFd, OwnedFd},
     sync::Arc,
 };
-use tokio::sync::{oneshot, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::instrument;
 use tracing::{Span, debug, trace};
 
@@ -57,6 +57,7 @@ pub(crate) struct InteractiveChildChip {
     original_command: String,
 
     status_receiver: watch::Receiver<Status>,
+    stdout_lines: Option<mpsc::UnboundedReceiver<String>>,
     stdout_handle: tokio::task::JoinHandle<Result<(), CommandError>>,
 }
 
@@ -179,6 +180,7 @@ pub(crate) async fn interactive_command_with_env<S: AsRef<str>>(
     let stdout_collection = Arc::new(Mutex::new(VecDeque::<String>::with_capacity(10)));
     let (began_tx, began_rx) = oneshot::channel::<()>();
     let (status_sender, status_receiver) = watch::channel(Status::Running);
+    let (stdout_sender, stdout_lines) = arguments.stdout_lines();
 
     let stdout_handle = {
         let arguments = WatchStdoutArguments {
@@ -188,6 +190,7 @@ pub(crate) async fn interactive_command_with_env<S: AsRef<str>>(
             output_mode: arguments.output_mode,
             stderr_collection: stderr_collection.clone(),
             stdout_collection: stdout_collection.clone(),
+            stdout_sender,
             span: Span::current(),
             log_stdout: arguments.log_stdout,
             status_sender,
@@ -238,6 +241,7 @@ pub(crate) async fn interactive_command_with_env<S: AsRef<str>>(
         stdout_collection,
         original_command: arguments.command_string.as_ref().to_string(),
         status_receiver,
+        stdout_lines,
         stdout_handle,
     })
 }
@@ -402,6 +406,10 @@ impl WireCommandChip for InteractiveChildChip {
 
         Ok(())
     }
+
+    async fn next_stdout_line(&mut self) -> Option<String> {
+        self.stdout_lines.as_mut()?.recv().await
+    }
 }
 
 impl StdinTermiosAttrGuard {
@@ -17,7 +17,7 @@ use std::{
     io::Write,
     sync::{Arc, Mutex},
 };
-use tokio::sync::{oneshot, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{Span, debug, instrument};
 
 pub(super) struct WatchStdoutArguments {
@@ -27,6 +27,7 @@ pub(super) struct WatchStdoutArguments {
     pub output_mode: ChildOutputMode,
     pub stderr_collection: Arc<Mutex<VecDeque<String>>>,
     pub stdout_collection: Arc<Mutex<VecDeque<String>>>,
+    pub stdout_sender: Option<mpsc::UnboundedSender<String>>,
     pub status_sender: watch::Sender<Status>,
     pub span: Span,
     pub log_stdout: bool,
@@ -46,6 +47,7 @@ pub(super) fn handle_pty_stdout(arguments: WatchStdoutArguments) -> Result<(), C
         needles,
         output_mode,
         stdout_collection,
+        stdout_sender,
         stderr_collection,
         status_sender,
         log_stdout,
@@ -126,6 +128,7 @@ pub(super) fn handle_pty_stdout(arguments: WatchStdoutArguments) -> Result<(), C
                     handle_normal_data(
                         &stderr_collection,
                         &stdout_collection,
+                        stdout_sender.as_ref(),
                         &mut line,
                         log_stdout,
                         output_mode,
@@ -185,6 +188,7 @@ pub(super) fn handle_rawmode_data<W: std::io::Write>(
 fn handle_normal_data(
     stderr_collection: &Arc<Mutex<VecDeque<String>>>,
     stdout_collection: &Arc<Mutex<VecDeque<String>>>,
+    stdout_sender: Option<&mpsc::UnboundedSender<String>>,
     line: &mut [u8],
     log_stdout: bool,
     output_mode: ChildOutputMode,
@@ -196,8 +200,15 @@ fn handle_normal_data(
             output_mode.trace_slice(stripped);
         }
 
+        let stripped = String::from_utf8_lossy(stripped).to_string();
+
+        if let Some(stdout_sender) = stdout_sender {
+            // the receiver may have stopped reading
+            let _ = stdout_sender.send(stripped.clone());
+        }
+
         let mut queue = stdout_collection.lock().unwrap();
-        queue.push_front(String::from_utf8_lossy(stripped).to_string());
+        queue.push_front(stripped);
         return;
     }
 
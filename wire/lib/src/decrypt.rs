@@ -0,0 +1,632 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use aes_gcm::{
+    AesGcm, KeyInit,
+    aead::{Aead, Payload, consts::U32},
+    aes::Aes256,
+};
+use age::{Identity, IdentityFile, armor::ArmoredReader};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+
+use crate::{
+    errors::KeyError,
+    hive::steps::keys::{AgeSource, SopsSource},
+};
+
+/// sops encrypts values with AES-256-GCM and 32 byte nonces
+type SopsCipher = AesGcm<Aes256, U32>;
+
+type Identities = Vec<Box<dyn Identity>>;
+
+/// Falls back to the identities sops would use.
+fn load_identities(configured: &[PathBuf]) -> Result<Identities, KeyError> {
+    let mut identities = Identities::new();
+    let mut paths = configured.to_vec();
+
+    if configured.is_empty() {
+        if let Ok(keys) = env::var("SOPS_AGE_KEY") {
+            let file = IdentityFile::from_buffer(keys.as_bytes()).map_err(|error| {
+                KeyError::AgeIdentityFile {
+                    path: "$SOPS_AGE_KEY".into(),
+                    error,
+                }
+            })?;
+
+            identities.extend(file.into_identities().map_err(|error| {
+                KeyError::AgeInvalidIdentity {
+                    path: "$SOPS_AGE_KEY".into(),
+                    error,
+                }
+            })?);
+        }
+
+        paths.extend(env::var_os("SOPS_AGE_KEY_FILE").map(PathBuf::from));
+        paths.extend(
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+                .map(|config| config.join("sops/age/keys.txt"))
+                .filter(|path| path.exists()),
+        );
+    }
+
+    for path in paths {
+        let file =
+            IdentityFile::from_file(path.to_string_lossy().into_owned()).map_err(|error| {
+                KeyError::AgeIdentityFile {
+                    path: path.clone(),
+                    error,
+                }
+            })?;
+
+        identities.extend(
+            file.into_identities()
+                .map_err(|error| KeyError::AgeInvalidIdentity { path, error })?,
+        );
+    }
+
+    if identities.is_empty() {
+        return Err(KeyError::AgeNoIdentities);
+    }
+
+    Ok(identities)
+}
+
+/// Decrypts binary or armored age ciphertext.
+fn decrypt_with(ciphertext: impl Read, identities: &Identities) -> Result<Vec<u8>, KeyError> {
+    let decryptor = age::Decryptor::new(ArmoredReader::new(BufReader::new(ciphertext)))
+        .map_err(KeyError::AgeDecrypt)?;
+    let mut reader = decryptor
+        .decrypt(
+            identities
+                .iter()
+                .map(|identity| identity.as_ref() as &dyn Identity),
+        )
+        .map_err(KeyError::AgeDecrypt)?;
+
+    let mut plaintext = Vec::new();
+    reader
+        .read_to_end(&mut plaintext)
+        .map_err(KeyError::AgePayload)?;
+
+    Ok(plaintext)
+}
+
+pub(crate) fn decrypt_age(source: &AgeSource) -> Result<Vec<u8>, KeyError> {
+    let identities = load_identities(&source.identities)?;
+    let file = fs::File::open(&source.file).map_err(KeyError::File)?;
+
+    decrypt_with(file, &identities)
+}
+
+/// Decrypts the data key from the first age recipient one of the identities
+/// matches.
+fn sops_data_key(metadata: &Value, identities: &[PathBuf]) -> Result<Vec<u8>, KeyError> {
+    let recipients = metadata
+        .get("age")
+        .and_then(Value::as_array)
+        .filter(|recipients| !recipients.is_empty())
+        .ok_or(KeyError::SopsNoAgeRecipients)?;
+    let identities = load_identities(identities)?;
+    let mut last_error = KeyError::SopsNoAgeRecipients;
+
+    for encrypted in recipients
+        .iter()
+        .filter_map(|recipient| recipient.get("enc").and_then(Value::as_str))
+    {
+        match decrypt_with(encrypted.as_bytes(), &identities) {
+            Ok(data_key) => return Ok(data_key),
+            Err(err) => last_error = err,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Decrypts a single `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`
+/// value, authenticated with `additional_data`. Returns the plaintext and its
+/// type.
+fn decrypt_sops_value<'a>(
+    value: &'a str,
+    additional_data: &str,
+    data_key: &[u8],
+) -> Option<(String, Option<&'a str>)> {
+    let fields = value
+        .strip_prefix("ENC[AES256_GCM,")
+        .and_then(|fields| fields.strip_suffix(']'))?;
+
+    let (mut data, mut iv, mut tag, mut kind) = (None, None, None, None);
+
+    for field in fields.split(',') {
+        match field.split_once(':') {
+            Some(("data", value)) => data = Some(value),
+            Some(("iv", value)) => iv = Some(value),
+            Some(("tag", value)) => tag = Some(value),
+            Some(("type", value)) => kind = Some(value),
+            _ => {}
+        }
+    }
+
+    let decode = |field: Option<&str>| BASE64_STANDARD.decode(field?).ok();
+
+    let mut ciphertext = decode(data)?;
+    ciphertext.extend(decode(tag)?);
+    let iv: [u8; 32] = decode(iv)?.try_into().ok()?;
+
+    let plaintext = SopsCipher::new_from_slice(data_key)
+        .ok()?
+        .decrypt(
+            (&iv).into(),
+            Payload {
+                msg: &ciphertext,
+                aad: additional_data.as_bytes(),
+            },
+        )
+        .ok()?;
+
+    Some((String::from_utf8(plaintext).ok()?, kind))
+}
+
+/// Converts a decrypted plaintext back to the type sops recorded for it.
+fn typed_value(plaintext: String, kind: Option<&str>) -> Option<Value> {
+    Some(match kind {
+        Some("int") => plaintext.parse::<i64>().ok()?.into(),
+        Some("float") => plaintext.parse::<f64>().ok()?.into(),
+        Some("bool") => Value::Bool(plaintext.eq_ignore_ascii_case("true")),
+        _ => Value::String(plaintext),
+    })
+}
+
+/// The bytes sops hashes into the file's MAC for an unencrypted value.
+fn mac_bytes(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.as_f64().filter(|_| number.is_f64()).map_or_else(
+            || number.to_string(),
+            // Go formats floats without an exponent, as Rust does
+            |float| float.to_string(),
+        )),
+        Value::Bool(true) => Some("True".to_string()),
+        Value::Bool(false) => Some("False".to_string()),
+        Value::Null | Value::Object(..) | Value::Array(..) => None,
+    }
+}
+
+/// Options of the sops MAC, which covers every value of the file in order.
+struct Mac {
+    hasher: Sha512,
+    only_encrypted: bool,
+}
+
+/// Decrypts every value in the tree, and hashes each value into `mac`.
+/// sops authenticates each value with the path of keys leading to it.
+fn decrypt_sops_tree(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    data_key: &[u8],
+    mac: &mut Mac,
+) -> Result<(), KeyError> {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                path.push(key.clone());
+                decrypt_sops_tree(child, path, data_key, mac)?;
+                path.pop();
+            }
+        }
+        // list items share the path of their list
+        Value::Array(items) => {
+            for item in items {
+                decrypt_sops_tree(item, path, data_key, mac)?;
+            }
+        }
+        Value::String(string) if string.starts_with("ENC[") => {
+            let invalid = || KeyError::SopsValue(path.join("."));
+            let additional_data = path
+                .iter()
+                .flat_map(|key| [key.as_str(), ":"])
+                .collect::<String>();
+            let (plaintext, kind) =
+                decrypt_sops_value(string, &additional_data, data_key).ok_or_else(invalid)?;
+
+            mac.hasher.update(plaintext.as_bytes());
+            *value = typed_value(plaintext, kind).ok_or_else(invalid)?;
+        }
+        value if !mac.only_encrypted => {
+            if let Some(bytes) = mac_bytes(value) {
+                mac.hasher.update(bytes.as_bytes());
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Checks the MAC sops stored in the metadata, encrypted with the file's
+/// last modified time, against the hash of the decrypted values.
+fn verify_mac(metadata: &Value, data_key: &[u8], mac: Mac) -> Result<(), KeyError> {
+    let field = |name| metadata.get(name).and_then(Value::as_str);
+    let (expected, _) = field("mac")
+        .zip(field("lastmodified"))
+        .and_then(|(mac, last_modified)| decrypt_sops_value(mac, last_modified, data_key))
+        .ok_or(KeyError::SopsMac)?;
+
+    let computed = mac
+        .hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02X}");
+            hex
+        });
+
+    if expected != computed {
+        return Err(KeyError::SopsMac);
+    }
+
+    Ok(())
+}
+
+/// Selects a value by a `.` separated path of keys, or list indices.
+fn select<'a>(document: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.')
+        .try_fold(document, |value, segment| match value {
+            Value::Object(map) => map.get(segment),
+            Value::Array(items) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get(index)),
+            _ => None,
+        })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SopsFormat {
+    Json,
+    Yaml,
+}
+
+/// sops picks the format of a file by its extension, and so does wire.
+fn sops_format(path: &Path) -> Result<SopsFormat, KeyError> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => Ok(SopsFormat::Json),
+        Some("yaml" | "yml") => Ok(SopsFormat::Yaml),
+        Some("env") => Err(KeyError::SopsUnsupportedFormat("dotenv".into())),
+        Some("ini") => Err(KeyError::SopsUnsupportedFormat("ini".into())),
+        _ => Err(KeyError::SopsUnsupportedFormat("binary".into())),
+    }
+}
+
+/// sops hashes YAML comments into the MAC, but serde drops them, so files
+/// with comments are decrypted by `sops` instead. Anything that may be a
+/// comment counts, as a false positive only costs running `sops`.
+fn has_yaml_comments(contents: &str) -> bool {
+    contents
+        .lines()
+        .any(|line| line.trim_start().starts_with('#') || line.contains(" #"))
+}
+
+/// Converts a `.` separated key to the `--extract` syntax of sops, `["a"][0]`.
+fn sops_extract(key: &str) -> String {
+    key.split('.')
+        .map(|segment| match segment.parse::<usize>() {
+            Ok(index) => format!("[{index}]"),
+            Err(_) => format!("[{}]", Value::from(segment)),
+        })
+        .collect()
+}
+
+fn decrypt_sops_command(source: &SopsSource) -> Result<Vec<u8>, KeyError> {
+    let mut command = Command::new("sops");
+    command.arg("--decrypt");
+
+    if let Some(key) = &source.key {
+        command.args(["--extract", &sops_extract(key)]);
+    }
+
+    if !source.identities.is_empty() {
+        let identities = source
+            .identities
+            .iter()
+            .map(|path| {
+                fs::read_to_string(path).map_err(|error| KeyError::AgeIdentityFile {
+                    path: path.clone(),
+                    error,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        command
+            .env("SOPS_AGE_KEY", identities.join("\n"))
+            .env_remove("SOPS_AGE_KEY_FILE");
+    }
+
+    let output = command
+        .arg(&source.file)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| KeyError::SopsCommand {
+            path: source.file.clone(),
+            error,
+        })?;
+
+    if !output.status.success() {
+        return Err(KeyError::CommandError(
+            output.status,
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+pub(crate) fn decrypt_sops(source: &SopsSource) -> Result<Vec<u8>, KeyError> {
+    let format = sops_format(&source.file)?;
+    let contents = fs::read_to_string(&source.file).map_err(KeyError::File)?;
+
+    if format == SopsFormat::Yaml && has_yaml_comments(&contents) {
+        return decrypt_sops_command(source);
+    }
+
+    let document: Value = match format {
+        SopsFormat::Yaml => serde_norway::from_str(&contents)
+            .map_err(|err| KeyError::SopsFormat(err.to_string()))?,
+        SopsFormat::Json => {
+            serde_json::from_str(&contents).map_err(|err| KeyError::SopsFormat(err.to_string()))?
+        }
+    };
+
+    let Value::Object(mut tree) = document else {
+        return Err(KeyError::SopsFormat(
+            "expected a map at the top level".into(),
+        ));
+    };
+
+    let metadata = tree
+        .remove("sops")
+        .ok_or_else(|| KeyError::SopsFormat("missing the `sops` metadata".into()))?;
+
+    // binary files are stored as json with a single `data` value
+    if tree.len() == 1 && tree.contains_key("data") {
+        return Err(KeyError::SopsUnsupportedFormat("binary".into()));
+    }
+
+    let data_key = sops_data_key(&metadata, &source.identities)?;
+    let mut mac = Mac {
+        hasher: Sha512::new(),
+        only_encrypted: metadata
+            .get("mac_only_encrypted")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+    };
+
+    let mut document = Value::Object(tree);
+    decrypt_sops_tree(&mut document, &mut Vec::new(), &data_key, &mut mac)?;
+    verify_mac(&metadata, &data_key, mac)?;
+
+    let selected = match &source.key {
+        Some(key) => {
+            select(&document, key).ok_or_else(|| KeyError::SopsKeyNotFound(key.clone()))?
+        }
+        None => &document,
+    };
+
+    Ok(match selected {
+        Value::String(string) => string.clone().into_bytes(),
+        Value::Object(..) | Value::Array(..) if format == SopsFormat::Yaml => {
+            serde_norway::to_string(selected)
+                .map_err(|err| KeyError::SopsFormat(err.to_string()))?
+                .into_bytes()
+        }
+        Value::Object(..) | Value::Array(..) => serde_json::to_vec_pretty(selected)
+            .map_err(|err| KeyError::SopsFormat(err.to_string()))?,
+        other => other.to_string().into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encrypt(plaintext: &str, kind: &str, additional_data: &str, data_key: &[u8]) -> String {
+        let iv = [7u8; 32];
+        let mut ciphertext = SopsCipher::new_from_slice(data_key)
+            .unwrap()
+            .encrypt(
+                (&iv).into(),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: additional_data.as_bytes(),
+                },
+            )
+            .unwrap();
+        // the 16 byte tag is appended to the ciphertext
+        let tag = ciphertext.split_off(ciphertext.len() - 16);
+
+        format!(
+            "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{kind}]",
+            BASE64_STANDARD.encode(ciphertext),
+            BASE64_STANDARD.encode(iv),
+            BASE64_STANDARD.encode(tag)
+        )
+    }
+
+    fn new_mac() -> Mac {
+        Mac {
+            hasher: Sha512::new(),
+            only_encrypted: false,
+        }
+    }
+
+    #[test]
+    fn test_decrypt_sops_tree() {
+        let data_key = [1u8; 32];
+        let mut document = json!({
+            "db": {
+                "password": encrypt("hunter2", "str", "db:password:", &data_key),
+                "port": encrypt("5432", "int", "db:port:", &data_key),
+                "hosts": [encrypt("a", "str", "db:hosts:", &data_key)],
+                "user_unencrypted": "admin",
+            }
+        });
+
+        decrypt_sops_tree(&mut document, &mut Vec::new(), &data_key, &mut new_mac()).unwrap();
+
+        assert_eq!(
+            document,
+            json!({
+                "db": {
+                    "password": "hunter2",
+                    "port": 5432,
+                    "hosts": ["a"],
+                    "user_unencrypted": "admin",
+                }
+            })
+        );
+        assert_eq!(select(&document, "db.password"), Some(&json!("hunter2")));
+        assert_eq!(select(&document, "db.hosts.0"), Some(&json!("a")));
+        assert_eq!(select(&document, "db.missing"), None);
+    }
+
+    #[test]
+    fn test_decrypt_sops_value_wrong_path() {
+        let data_key = [1u8; 32];
+        let value = encrypt("hunter2", "str", "db:password:", &data_key);
+
+        assert_eq!(decrypt_sops_value(&value, "password:", &data_key), None);
+        assert_eq!(
+            decrypt_sops_value(&value, "db:password:", &data_key),
+            Some(("hunter2".to_string(), Some("str")))
+        );
+    }
+
+    #[test]
+    fn test_verify_mac() {
+        let data_key = [1u8; 32];
+        let last_modified = "2025-01-01T00:00:00Z";
+        let document = || {
+            json!({
+                "password": encrypt("hunter2", "str", "password:", &data_key),
+                "enabled": true,
+                "user_unencrypted": "admin",
+            })
+        };
+        // every value in document order, as sops hashes them
+        let expected =
+            Sha512::digest(b"hunter2Trueadmin")
+                .iter()
+                .fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02X}");
+                    hex
+                });
+        let metadata = |mac: &str| {
+            json!({
+                "mac": encrypt(mac, "str", last_modified, &data_key),
+                "lastmodified": last_modified,
+            })
+        };
+
+        let mut mac = new_mac();
+        decrypt_sops_tree(&mut document(), &mut Vec::new(), &data_key, &mut mac).unwrap();
+        assert!(verify_mac(&metadata(&expected), &data_key, mac).is_ok());
+
+        let mut mac = new_mac();
+        let mut tampered = document();
+        tampered["user_unencrypted"] = json!("root");
+        decrypt_sops_tree(&mut tampered, &mut Vec::new(), &data_key, &mut mac).unwrap();
+        assert!(matches!(
+            verify_mac(&metadata(&expected), &data_key, mac),
+            Err(KeyError::SopsMac)
+        ));
+
+        let mut mac = new_mac();
+        decrypt_sops_tree(&mut document(), &mut Vec::new(), &data_key, &mut mac).unwrap();
+        assert!(matches!(
+            verify_mac(&json!({}), &data_key, mac),
+            Err(KeyError::SopsMac)
+        ));
+    }
+
+    #[test]
+    fn test_sops_format() {
+        assert!(matches!(
+            sops_format(Path::new("secrets.yaml")),
+            Ok(SopsFormat::Yaml)
+        ));
+        assert!(matches!(
+            sops_format(Path::new("secrets.yml")),
+            Ok(SopsFormat::Yaml)
+        ));
+        assert!(matches!(
+            sops_format(Path::new("secrets.json")),
+            Ok(SopsFormat::Json)
+        ));
+
+        for path in ["secrets.env", "secrets.ini", "secrets.bin", "secrets"] {
+            assert!(matches!(
+                sops_format(Path::new(path)),
+                Err(KeyError::SopsUnsupportedFormat(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_has_yaml_comments() {
+        assert!(!has_yaml_comments(
+            "password: ENC[AES256_GCM,data:AAAA,iv:AAAA,tag:AAAA,type:str]\nsops:\n    mac: ENC[AES256_GCM,data:AAAA,iv:AAAA,tag:AAAA,type:str]\n"
+        ));
+        assert!(has_yaml_comments(
+            "#ENC[AES256_GCM,data:AAAA,iv:AAAA,tag:AAAA,type:comment]\npassword: ENC[AES256_GCM,data:AAAA,iv:AAAA,tag:AAAA,type:str]\n"
+        ));
+        assert!(has_yaml_comments(
+            "db:\n    # the primary\n    host: db-1\n"
+        ));
+        assert!(has_yaml_comments("host: db-1 # the primary\n"));
+    }
+
+    #[test]
+    fn test_sops_extract() {
+        assert_eq!(sops_extract("password"), r#"["password"]"#);
+        assert_eq!(sops_extract("db.hosts.0"), r#"["db"]["hosts"][0]"#);
+        assert_eq!(sops_extract(r#"a"b"#), r#"["a\"b"]"#);
+    }
+
+    #[test]
+    fn test_decrypt_sops_binary() {
+        let directory = tempdir::TempDir::new("wire-sops").unwrap();
+        let file = directory.path().join("secret.json");
+
+        fs::write(
+            &file,
+            json!({
+                "data": "ENC[AES256_GCM,data:AAAA,iv:AAAA,tag:AAAA,type:str]",
+                "sops": {},
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            decrypt_sops(&SopsSource {
+                file,
+                key: None,
+                identities: Vec::new(),
+            }),
+            Err(KeyError::SopsUnsupportedFormat(..))
+        ));
+    }
+}
@@ -70,6 +70,13 @@ pub enum KeyError {
     #[error("Command list empty")]
     Empty,
 
+    #[diagnostic(
+        code(wire::key::DecryptJoin),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to join on the decryption task")]
+    DecryptJoin(#[source] JoinError),
+
     #[diagnostic(
         code(wire::key::ParseKeyPermissions),
         help("Refer to the documentation for the format of key file permissions."),
@@ -77,6 +84,191 @@ pub enum KeyError {
     )]
     #[error("Failed to parse key permissions")]
     ParseKeyPermissions(#[source] ParseIntError),
+
+    #[diagnostic(
+        code(wire::key::AgeNoIdentities),
+        help("Set `identities` on the key's source, set `$SOPS_AGE_KEY_FILE` or `$SOPS_AGE_KEY`, or place your identities in ~/.config/sops/age/keys.txt"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("No age identities were found to decrypt the key")]
+    AgeNoIdentities,
+
+    #[diagnostic(
+        code(wire::key::AgeIdentityFile),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to read age identity file {}", .path.display())]
+    AgeIdentityFile {
+        path: PathBuf,
+
+        #[source]
+        error: std::io::Error,
+    },
+
+    #[diagnostic(
+        code(wire::key::AgeInvalidIdentity),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("{} does not contain valid age identities", .path.display())]
+    AgeInvalidIdentity {
+        path: PathBuf,
+
+        #[source]
+        error: age::DecryptError,
+    },
+
+    #[diagnostic(
+        code(wire::key::AgeDecrypt),
+        help("Ensure the key was encrypted to one of your identities"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to decrypt age ciphertext")]
+    AgeDecrypt(#[source] age::DecryptError),
+
+    #[diagnostic(
+        code(wire::key::AgePayload),
+        help("The ciphertext is corrupt or truncated"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to read the decrypted age payload")]
+    AgePayload(#[source] std::io::Error),
+
+    #[diagnostic(
+        code(wire::key::SopsFormat),
+        help("wire can decrypt sops files in the JSON and YAML formats"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to read sops file: {0}")]
+    SopsFormat(String),
+
+    #[diagnostic(
+        code(wire::key::SopsUnsupportedFormat),
+        help("wire can decrypt sops files in the JSON and YAML formats, named `*.json`, `*.yaml` or `*.yml`. Use a command source running `sops -d` for other formats."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("wire cannot decrypt sops files in the {0} format")]
+    SopsUnsupportedFormat(String),
+
+    #[diagnostic(
+        code(wire::key::SopsNoAgeRecipients),
+        help("wire can only decrypt sops files encrypted for age. Use a command source running `sops -d` for other key types."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The sops file has no age recipients")]
+    SopsNoAgeRecipients,
+
+    #[diagnostic(
+        code(wire::key::SopsValue),
+        help("The sops file is corrupt, or was modified without sops"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to decrypt the sops value at `{0}`")]
+    SopsValue(String),
+
+    #[diagnostic(
+        code(wire::key::SopsMac),
+        help("The sops file is corrupt, or was modified without sops"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The sops file failed its MAC check")]
+    SopsMac,
+
+    #[diagnostic(
+        code(wire::key::SopsCommand),
+        help("wire cannot check the MAC of sops YAML files with comments, so it decrypts them with `sops`. Add sops to wire's $PATH, or remove the comments from the file"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to run `sops` to decrypt {}, a YAML file with comments", .path.display())]
+    SopsCommand {
+        path: PathBuf,
+
+        #[source]
+        error: std::io::Error,
+    },
+
+    #[diagnostic(
+        code(wire::key::SopsKeyNotFound),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("`{0}` was not found in the sops file")]
+    SopsKeyNotFound(String),
+
+    #[diagnostic(
+        code(wire::key::TemplatePlaceholder),
+        help("Placeholders are `node.name`, `node.host`, `node.tags` and `values.<name>` for each of the template's values"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Unknown template placeholder `{{{{ {0} }}}}`")]
+    TemplatePlaceholder(String),
+
+    #[diagnostic(
+        code(wire::key::TemplateUnclosed),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Template has a `{{{{` without a closing `}}}}`")]
+    TemplateUnclosed,
+
+    #[diagnostic(
+        code(wire::key::TemplateValueNotUtf8),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Template value `{0}` is not valid UTF-8")]
+    TemplateValueNotUtf8(String),
+
+    #[diagnostic(
+        code(wire::key::TemplateNested),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Template values cannot be templates themselves")]
+    TemplateNested,
+
+    #[diagnostic(
+        code(wire::key::UnelevatedOwner),
+        help("Keys with `elevate = false` are written by the SSH user, and must be owned by them"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Key is owned by {user}, but would be written by the SSH user {ssh_user}")]
+    UnelevatedOwner { user: String, ssh_user: String },
+
+    #[diagnostic(
+        code(wire::key::ChangedDuringUpload),
+        help("The key's file was modified while wire was uploading it. The upload was rolled back, try again"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Key changed during upload")]
+    ChangedDuringUpload,
+
+    #[diagnostic(
+        code(wire::key::AgentProtocolVersion),
+        help("The key agent pushed to the node was built for another version of wire. Ensure `WIRE_KEY_AGENT_*` points to the agent built with this wire"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The key agent speaks protocol version {agent}, but wire speaks {wire}")]
+    AgentProtocolVersion { agent: u32, wire: u32 },
+
+    #[diagnostic(
+        code(wire::key::AgentCapabilities),
+        help("The key agent pushed to the node was built for another version of wire. Ensure `WIRE_KEY_AGENT_*` points to the agent built with this wire"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The key agent does not support: {0}")]
+    AgentCapabilities(String),
+
+    #[diagnostic(
+        code(wire::key::AgentNoHello),
+        help("The key agent pushed to the node predates the protocol handshake. Ensure `WIRE_KEY_AGENT_*` points to the agent built with this wire"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The key agent did not send a hello")]
+    AgentNoHello,
+
+    #[diagnostic(
+        code(wire::key::AgentNoKeyState),
+        help("The key agent pushed to the node was built for another version of wire. Ensure `WIRE_KEY_AGENT_*` points to the agent built with this wire"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The key agent exited without reporting the state of: {0}")]
+    AgentNoKeyState(String),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -87,6 +279,43 @@ pub enum ActivationError {
     )]
     #[error("failed to run switch-to-configuration {0} on node {1}")]
     SwitchToConfigurationError(SwitchToConfigurationGoal, Name, #[source] CommandError),
+
+    #[diagnostic(
+        code(wire::activation::HealthCheck),
+        help("If `deployment.autoRollback` is enabled, the node was switched back to its previous system."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("health check {check} failed on node {name}")]
+    HealthCheckFailed {
+        name: Name,
+        check: String,
+        #[source]
+        source: Box<CommandError>,
+    },
+
+    #[diagnostic(
+        code(wire::activation::UnknownProfile),
+        help("Set `deployment.replaceUnknownProfiles = true` or pass `--replace-unknown-profiles` to replace it anyway. Nodes last deployed by an older wire must be deployed once with `--replace-unknown-profiles` to record their profile."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("refusing to replace the system profile of node {0} ({1}), which was not set by wire")]
+    UnknownProfile(Name, String),
+
+    #[diagnostic(
+        code(wire::activation::MagicRollbackUnconfirmed),
+        help("The node will switch back to its previous system once `deployment.confirmTimeout` elapses."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to confirm activation of node {0}")]
+    MagicRollbackUnconfirmed(Name),
+
+    #[diagnostic(
+        code(wire::activation::Rollback),
+        help("The node may be left in a broken state and require manual intervention."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to roll back node {0} to {1}")]
+    RollbackFailed(Name, String, #[source] Box<HiveLibError>),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -147,6 +376,37 @@ pub enum HiveInitialisationError {
     )]
     #[error("node {0} not exist in hive")]
     NodeDoesNotExist(String),
+
+    #[diagnostic(
+        code(wire::hive_init::UnknownDependency),
+        help("`deployment.dependsOn` must only list the names of other nodes in the hive"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {node} depends on {dependency}, which does not exist in the hive")]
+    UnknownDependency { node: Name, dependency: Name },
+
+    #[diagnostic(
+        code(wire::hive_init::UnknownBuilder),
+        help("`deployment.buildOn` must be the name of another node in the hive, or a list of builders"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {node} is built on {builder}, which does not exist in the hive")]
+    UnknownBuilder { node: Name, builder: Name },
+
+    #[diagnostic(
+        code(wire::hive_init::RuntimeNotFound),
+        help("Set `WIRE_RUNTIME` to the `runtime` directory of this wire, or pass `--no-eval-cache`"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("wire's runtime was not found at {}, the evaluation cache cannot be keyed on it", .0.display())]
+    RuntimeNotFound(PathBuf),
+
+    #[diagnostic(
+        code(wire::hive_init::DependencyCycle),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("nodes depend on each other in a cycle: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(" -> "))]
+    DependencyCycle(Vec<Name>),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -283,6 +543,59 @@ pub enum CommandError {
     )]
     #[error("Error waiting for begin message")]
     OneshotRecvError(#[source] tokio::sync::oneshot::error::RecvError),
+
+    /// Only raised by the native SSH backend.
+    #[cfg(feature = "native-ssh")]
+    #[diagnostic(
+        code(wire::command::NativeSshConnect),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to establish an SSH session with {host}")]
+    NativeSshConnect {
+        host: String,
+
+        #[source]
+        source: Box<russh::Error>,
+    },
+
+    /// Only raised by the native SSH backend, which authenticates with the
+    /// keys held by `ssh-agent`, identity files given in
+    /// `deployment.target.sshOptions`, and the default keys in `~/.ssh`.
+    #[cfg(feature = "native-ssh")]
+    #[diagnostic(
+        code(wire::command::NativeSshAuth),
+        help("Ensure your key is loaded into ssh-agent, or pass it with `-i` in `deployment.target.sshOptions`"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("No key was accepted for {user}@{host}")]
+    NativeSshAuth { user: String, host: String },
+
+    /// Only raised by the native SSH backend, which does not read
+    /// `ssh_config` options.
+    #[cfg(feature = "native-ssh")]
+    #[diagnostic(
+        code(wire::command::NativeSshUnsupportedOption),
+        help("The native SSH backend only supports `-i` and `-o IdentityFile=` in `deployment.target.sshOptions`. Use `--ssh-backend openssh` for this node"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The native SSH backend does not support the ssh option `{0}`")]
+    NativeSshUnsupportedOption(String),
+
+    #[cfg(feature = "native-ssh")]
+    #[diagnostic(
+        code(wire::command::NativeSshChannel),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("There was an error on an SSH channel")]
+    NativeSshChannel(#[source] Box<russh::Error>),
+
+    #[cfg(feature = "native-ssh")]
+    #[diagnostic(
+        code(wire::command::NativeSshChannelClosed),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("The SSH channel was closed before all of stdin was written")]
+    NativeSshChannelClosed,
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -315,6 +628,14 @@ pub enum HiveLibError {
         KeyError,
     ),
 
+    #[error("Key agent on node {0} is incompatible with wire")]
+    KeyAgentHandshake(
+        Name,
+        #[source]
+        #[diagnostic_source]
+        KeyError,
+    ),
+
     #[diagnostic(
         code(wire::BuildNode),
         url("{DOCS_URL}#{}", self.code().unwrap())
@@ -326,6 +647,17 @@ pub enum HiveLibError {
         source: CommandError,
     },
 
+    #[diagnostic(
+        code(wire::BuildNodes),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to build {count} nodes at once")]
+    NixBatchBuildError {
+        count: usize,
+        #[source]
+        source: CommandError,
+    },
+
     #[diagnostic(
         code(wire::CopyPath),
         url("{DOCS_URL}#{}", self.code().unwrap())
@@ -352,10 +684,38 @@ pub enum HiveLibError {
         help: Option<Box<String>>,
     },
 
+    #[diagnostic(
+        code(wire::PathInfo),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to query the closure of {path} on node {name}")]
+    NixPathInfoError {
+        name: Name,
+        path: String,
+        #[source]
+        source: Box<CommandError>,
+    },
+
+    #[diagnostic(
+        code(wire::ParsePathInfo),
+        help("If you cannot resolve this problem, please create an issue."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to parse the output of `nix path-info`")]
+    ParsePathInfo(#[source] serde_json::Error),
+
     #[diagnostic(
         code(wire::Encoding),
         url("{DOCS_URL}#{}", self.code().unwrap())
     )]
     #[error("error encoding length delimited data")]
     Encoding(#[source] std::io::Error),
+
+    #[diagnostic(
+        code(wire::KeysDrifted),
+        help("Run `wire apply keys` to upload the keys again"),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("{count} key(s) on node {name} differ from the hive")]
+    KeysDrifted { name: Name, count: usize },
 }
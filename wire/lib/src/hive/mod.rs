@@ -3,7 +3,7 @@
 
 use itertools::Itertools;
 use nix_compat::flakeref::FlakeRef;
-use node::{Name, Node};
+use node::{BuildOn, Name, Node, Target};
 use owo_colors::{OwoColorize, Stream};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -59,13 +59,96 @@ impl Hive {
 
         let output = evaluate_hive_attribute(location, &EvalGoal::Inspect, modifiers).await?;
 
-        let hive: Hive = serde_json::from_str(&output).map_err(|err| {
+        let mut hive: Hive = serde_json::from_str(&output).map_err(|err| {
             HiveLibError::HiveInitialisationError(HiveInitialisationError::ParseEvaluateError(err))
         })?;
 
+        hive.check_dependencies()
+            .map_err(HiveLibError::HiveInitialisationError)?;
+        hive.resolve_builders()
+            .map_err(HiveLibError::HiveInitialisationError)?;
+
         Ok(hive)
     }
 
+    /// Resolves each `deployment.buildOn` node to its target.
+    pub fn resolve_builders(&mut self) -> Result<(), HiveInitialisationError> {
+        let targets: HashMap<Name, Target> = self
+            .nodes
+            .iter()
+            .map(|(name, node)| (name.clone(), node.target.clone()))
+            .collect();
+
+        for (name, node) in &mut self.nodes {
+            if let Some(BuildOn::Node(builder)) = &node.build_on {
+                node.builder = Some(targets.get(builder).cloned().ok_or_else(|| {
+                    HiveInitialisationError::UnknownBuilder {
+                        node: name.clone(),
+                        builder: builder.clone(),
+                    }
+                })?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every `deployment.dependsOn` entry is a node in the hive,
+    /// and that no nodes depend on each other in a cycle.
+    pub fn check_dependencies(&self) -> Result<(), HiveInitialisationError> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Visit {
+            InProgress,
+            Done,
+        }
+
+        fn visit<'a>(
+            hive: &'a Hive,
+            name: &'a Name,
+            visits: &mut HashMap<&'a Name, Visit>,
+            path: &mut Vec<&'a Name>,
+        ) -> Result<(), HiveInitialisationError> {
+            match visits.get(name) {
+                Some(Visit::Done) => return Ok(()),
+                Some(Visit::InProgress) => {
+                    let start = path.iter().position(|x| *x == name).unwrap_or_default();
+                    let mut cycle: Vec<Name> = path[start..].iter().map(|x| (*x).clone()).collect();
+                    cycle.push(name.clone());
+
+                    return Err(HiveInitialisationError::DependencyCycle(cycle));
+                }
+                None => {}
+            }
+
+            visits.insert(name, Visit::InProgress);
+            path.push(name);
+
+            for dependency in &hive.nodes[name].depends_on {
+                if !hive.nodes.contains_key(dependency) {
+                    return Err(HiveInitialisationError::UnknownDependency {
+                        node: name.clone(),
+                        dependency: dependency.clone(),
+                    });
+                }
+
+                visit(hive, dependency, visits, path)?;
+            }
+
+            path.pop();
+            visits.insert(name, Visit::Done);
+
+            Ok(())
+        }
+
+        let mut visits = HashMap::new();
+
+        for name in self.nodes.keys().sorted_by(|a, b| a.0.cmp(&b.0)) {
+            visit(self, name, &mut visits, &mut Vec::new())?;
+        }
+
+        Ok(())
+    }
+
     /// # Errors
     ///
     /// Returns an error if a node in nodes does not exist in the hive.
@@ -73,18 +156,33 @@ impl Hive {
         for node in nodes {
             info!("Forcing a local build for {node}");
 
-            self.nodes
-                .get_mut(&Name(Arc::from(node.clone())))
-                .ok_or(HiveLibError::HiveInitialisationError(
-                    HiveInitialisationError::NodeDoesNotExist(node.clone()),
-                ))?
-                .build_remotely = false;
+            let entry = self.nodes.get_mut(&Name(Arc::from(node.clone()))).ok_or(
+                HiveLibError::HiveInitialisationError(HiveInitialisationError::NodeDoesNotExist(
+                    node.clone(),
+                )),
+            )?;
+
+            entry.build_remotely = false;
+            entry.build_on = None;
         }
 
         Ok(())
     }
 }
 
+/// Writes the label of a node option, followed by the nix option it is set by.
+fn write_option(f: &mut std::fmt::Formatter<'_>, label: &str, option: &str) -> std::fmt::Result {
+    write!(
+        f,
+        " > {} {}{}",
+        label.bold(),
+        format!("`{option}`")
+            .if_supports_color(Stream::Stdout, |x| x.dimmed())
+            .italic(),
+        ":".bold()
+    )
+}
+
 impl Display for Hive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (name, node) in &self.nodes {
@@ -105,28 +203,40 @@ impl Display for Hive {
             write!(f, " > {}", "Connection:".bold())?;
             writeln!(f, " {{{}}}", node.target)?;
 
-            write!(
-                f,
-                " > {} {}{}",
-                "Build remotely".bold(),
-                "`deployment.buildOnTarget`"
-                    .if_supports_color(Stream::Stdout, |x| x.dimmed())
-                    .italic(),
-                ":".bold()
-            )?;
+            write_option(f, "Build remotely", "deployment.buildOnTarget")?;
             writeln!(f, " {}", node.build_remotely)?;
 
-            write!(
-                f,
-                " > {} {}{}",
-                "Local apply allowed".bold(),
-                "`deployment.allowLocalDeployment`"
-                    .if_supports_color(Stream::Stdout, |x| x.dimmed())
-                    .italic(),
-                ":".bold()
-            )?;
+            if let Some(build_on) = &node.build_on {
+                write_option(f, "Build on", "deployment.buildOn")?;
+
+                match build_on {
+                    BuildOn::Node(builder) => writeln!(f, " {builder}")?,
+                    BuildOn::Builders(builders) => writeln!(f, " {}", builders.iter().join("; "))?,
+                }
+            }
+
+            write_option(f, "Local apply allowed", "deployment.allowLocalDeployment")?;
             writeln!(f, " {}", node.allow_local_deployment)?;
 
+            if node.magic_rollback {
+                write_option(f, "Magic rollback", "deployment.magicRollback")?;
+                writeln!(f, " confirm within {}s", node.confirm_timeout)?;
+            }
+
+            if !node.health_checks.is_empty() {
+                write_option(f, "Health checks", "deployment.healthChecks")?;
+                writeln!(
+                    f,
+                    " {} check(s), rollback {}",
+                    node.health_checks.len(),
+                    if node.auto_rollback {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                )?;
+            }
+
             if !node.keys.is_empty() {
                 write!(f, " > {}", "Keys:".bold())?;
                 writeln!(f, " {} key(s)", node.keys.len())?;
@@ -280,7 +390,11 @@ mod tests {
                 permissions: "0600".into(),
                 source: Source::String("hi".into()),
                 upload_at: UploadKeyAt::PreActivation,
-                environment: im::HashMap::new()
+                environment: im::HashMap::new(),
+                restart_units: im::Vector::new(),
+                reload_units: im::Vector::new(),
+                elevate: true,
+                cache: None,
             }],
             build_remotely: true,
             ..Default::default()
@@ -360,6 +474,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_check_dependencies() {
+        let node = |depends_on: &[&str]| Node {
+            depends_on: depends_on.iter().map(|x| Name((*x).into())).collect(),
+            ..Default::default()
+        };
+
+        let mut hive = Hive {
+            nodes: HashMap::from([
+                (Name("db".into()), node(&[])),
+                (Name("web".into()), node(&["db"])),
+            ]),
+            schema: Hive::SCHEMA_VERSION,
+        };
+
+        assert_matches!(hive.check_dependencies(), Ok(()));
+
+        hive.nodes
+            .insert(Name("cache".into()), node(&["does-not-exist"]));
+
+        assert_matches!(
+            hive.check_dependencies(),
+            Err(HiveInitialisationError::UnknownDependency { node, dependency })
+                if node.0.as_ref() == "cache" && dependency.0.as_ref() == "does-not-exist"
+        );
+
+        hive.nodes.insert(Name("cache".into()), node(&["web"]));
+        hive.nodes.insert(Name("db".into()), node(&["cache"]));
+
+        assert_matches!(
+            hive.check_dependencies(),
+            Err(HiveInitialisationError::DependencyCycle(cycle))
+                if cycle.len() == 4 && cycle.first() == cycle.last()
+        );
+    }
+
+    #[test]
+    fn test_resolve_builders() {
+        let mut hive = Hive {
+            nodes: HashMap::from([
+                (
+                    Name("builder".into()),
+                    Node {
+                        target: Target::from_host("builder.example.com"),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    Name("web".into()),
+                    Node {
+                        build_on: Some(BuildOn::Node(Name("builder".into()))),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            schema: Hive::SCHEMA_VERSION,
+        };
+
+        assert_matches!(hive.resolve_builders(), Ok(()));
+        assert_eq!(
+            hive.nodes.get(&Name("web".into())).unwrap().builder,
+            Some(Target::from_host("builder.example.com"))
+        );
+
+        hive.nodes.insert(
+            Name("cache".into()),
+            Node {
+                build_on: Some(BuildOn::Node(Name("does-not-exist".into()))),
+                ..Default::default()
+            },
+        );
+
+        assert_matches!(
+            hive.resolve_builders(),
+            Err(HiveInitialisationError::UnknownBuilder { node, builder })
+                if node.0.as_ref() == "cache" && builder.0.as_ref() == "does-not-exist"
+        );
+    }
+
     #[tokio::test]
     async fn test_force_always_local() {
         let mut location: PathBuf = env::var("WIRE_TEST_DIR").unwrap().into();
@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use std::assert_matches::debug_assert_matches;
 use std::fmt::Display;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::oneshot;
 use tracing::{Instrument, Level, Span, debug, error, event, instrument, trace};
 
@@ -15,10 +16,14 @@ use crate::commands::common::evaluate_hive_attribute;
 use crate::commands::{CommandArguments, WireCommandChip, run_command};
 use crate::errors::NetworkError;
 use crate::hive::HiveLocation;
-use crate::hive::steps::build::Build;
+use crate::hive::steps::build::{BatchedBuild, Build};
 use crate::hive::steps::cleanup::CleanUp;
-use crate::hive::steps::evaluate::Evaluate;
-use crate::hive::steps::keys::{Key, Keys, PushKeyAgent, UploadKeyAt};
+use crate::hive::steps::diff::{Diff, SystemDiff};
+use crate::hive::steps::evaluate::{BatchedEvaluation, EvalCache, Evaluate};
+use crate::hive::steps::health::{HealthCheck, HealthChecks};
+use crate::hive::steps::keys::{
+    Key, KeyDrift, Keys, PendingUnits, PushKeyAgent, UploadKeyAt, VerifyKeys,
+};
 use crate::hive::steps::ping::Ping;
 use crate::hive::steps::push::{PushBuildOutput, PushEvaluatedOutput};
 use crate::status::STATUS;
@@ -36,6 +41,9 @@ pub struct Target {
     pub user: Arc<str>,
     pub port: u32,
 
+    #[serde(rename = "sshOptions")]
+    pub ssh_options: Vec<String>,
+
     #[serde(skip)]
     current_host: usize,
 }
@@ -78,6 +86,10 @@ impl Target {
         options.extend(["PasswordAuthentication=no".to_string()]);
         options.extend(["KbdInteractiveAuthentication=no".to_string()]);
 
+        // ssh uses the first value it sees for an option, so user provided
+        // options come first to override the defaults below
+        vector.extend(self.ssh_options.iter().cloned());
+
         vector.push("-o".to_string());
         vector.extend(options.into_iter().intersperse("-o".to_string()));
 
@@ -92,6 +104,7 @@ impl Default for Target {
             hosts: vec!["NAME".into()],
             user: "root".into(),
             port: 22,
+            ssh_options: Vec::new(),
             current_host: 0,
         }
     }
@@ -115,6 +128,10 @@ impl<'a> Context<'a> {
             reboot: false,
             should_apply_locally: false,
             handle_unreachable: HandleUnreachable::default(),
+            replace_unknown_profiles: false,
+            batched_evaluation: None,
+            eval_cache: None,
+            batched_build: None,
         }
     }
 }
@@ -155,6 +172,7 @@ impl Display for Target {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Node {
     #[serde(rename = "target")]
     pub target: Target,
@@ -162,12 +180,23 @@ pub struct Node {
     #[serde(rename = "buildOnTarget")]
     pub build_remotely: bool,
 
+    #[serde(rename = "buildOn", default)]
+    pub build_on: Option<BuildOn>,
+
+    /// The target of the node named by `build_on`, set by
+    /// `Hive::resolve_builders`.
+    #[serde(skip)]
+    pub builder: Option<Target>,
+
     #[serde(rename = "allowLocalDeployment")]
     pub allow_local_deployment: bool,
 
     #[serde(default)]
     pub tags: im::HashSet<String>,
 
+    #[serde(rename = "dependsOn", default)]
+    pub depends_on: im::Vector<Name>,
+
     #[serde(rename(deserialize = "_keys", serialize = "keys"))]
     pub keys: im::Vector<Key>,
 
@@ -179,6 +208,40 @@ pub struct Node {
         serialize = "privilege_escalation_command"
     ))]
     pub privilege_escalation_command: im::Vector<Arc<str>>,
+
+    #[serde(rename = "replaceUnknownProfiles")]
+    pub replace_unknown_profiles: bool,
+
+    #[serde(rename = "autoRollback")]
+    pub auto_rollback: bool,
+
+    #[serde(rename = "magicRollback")]
+    pub magic_rollback: bool,
+
+    #[serde(rename = "confirmTimeout")]
+    pub confirm_timeout: u32,
+
+    #[serde(rename = "healthChecks")]
+    pub health_checks: HealthChecks,
+}
+
+/// `deployment.buildOn`
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[serde(untagged)]
+pub enum BuildOn {
+    /// Another node of the hive, that the derivation is pushed to
+    Node(Name),
+    /// Machines passed to `nix build --builders`
+    Builders(im::Vector<String>),
+}
+
+/// Where a node's system is built, see `Node::build_location`.
+#[derive(Debug, PartialEq)]
+pub enum BuildLocation<'a> {
+    Local,
+    Target,
+    Builder(&'a Target),
+    Builders(&'a im::Vector<String>),
 }
 
 #[cfg(test)]
@@ -188,10 +251,18 @@ impl Default for Node {
             target: Target::default(),
             keys: im::Vector::new(),
             tags: im::HashSet::new(),
+            depends_on: im::Vector::new(),
             privilege_escalation_command: vec!["sudo".into(), "--".into()].into(),
             allow_local_deployment: true,
             build_remotely: false,
+            build_on: None,
+            builder: None,
             host_platform: "x86_64-linux".into(),
+            replace_unknown_profiles: true,
+            auto_rollback: false,
+            magic_rollback: false,
+            confirm_timeout: 300,
+            health_checks: HealthChecks::default(),
         }
     }
 }
@@ -206,10 +277,49 @@ impl Node {
         }
     }
 
+    /// `deployment.buildOn` takes precedence over `deployment.buildOnTarget`.
+    #[must_use]
+    pub const fn build_location(&self, should_apply_locally: bool) -> BuildLocation<'_> {
+        // building remotely but applying locally does not logically make any
+        // sense
+        if should_apply_locally {
+            return BuildLocation::Local;
+        }
+
+        match (&self.build_on, &self.builder) {
+            (Some(BuildOn::Node(_)), Some(builder)) => BuildLocation::Builder(builder),
+            (Some(BuildOn::Builders(builders)), _) => BuildLocation::Builders(builders),
+            _ if self.build_remotely => BuildLocation::Target,
+            _ => BuildLocation::Local,
+        }
+    }
+
     /// Tests the connection to a node
     pub async fn ping(&self, modifiers: SubCommandModifiers) -> Result<(), HiveLibError> {
         let host = self.target.get_preferred_host()?;
 
+        // opening (or reusing) the session is the ping
+        #[cfg(feature = "native-ssh")]
+        if matches!(modifiers.ssh_backend, crate::SshBackend::Native) {
+            let output = run_command(
+                &CommandArguments::new("exit", modifiers).on_target(Some(&self.target)),
+            )
+            .await;
+
+            let result = match output {
+                Ok(output) => output.wait_till_success().await.map(drop),
+                Err(HiveLibError::CommandError(source)) => Err(source),
+                Err(err) => return Err(err),
+            };
+
+            return result.map_err(|source| {
+                HiveLibError::NetworkError(NetworkError::HostUnreachable {
+                    host: host.to_string(),
+                    source,
+                })
+            });
+        }
+
         let command_string = format!(
             "ssh {}@{host} {} exit",
             self.target.user,
@@ -246,7 +356,7 @@ pub enum Push<'a> {
 }
 
 #[derive(Deserialize, Clone, Debug)]
-pub struct Derivation(String);
+pub struct Derivation(pub(crate) String);
 
 impl Display for Derivation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -268,6 +378,8 @@ pub enum Goal {
     Build,
     Push,
     Keys,
+    Diff,
+    VerifyKeys,
 }
 
 #[enum_dispatch]
@@ -292,8 +404,14 @@ pub struct StepState {
     pub evaluation_rx: Option<oneshot::Receiver<Result<Derivation, HiveLibError>>>,
     pub build: Option<String>,
     pub key_agent_directory: Option<String>,
+    pub previous_system: Option<String>,
+    /// Units of keys uploaded before activation, restarted once it finished
+    pub pending_units: PendingUnits,
+    pub diff: Option<SystemDiff>,
+    pub key_drift: Option<Vec<KeyDrift>>,
 }
 
+#[allow(clippy::struct_excessive_bools)]
 pub struct Context<'a> {
     pub name: &'a Name,
     pub node: &'a mut Node,
@@ -305,6 +423,21 @@ pub struct Context<'a> {
     pub reboot: bool,
     pub should_apply_locally: bool,
     pub handle_unreachable: HandleUnreachable,
+    pub replace_unknown_profiles: bool,
+    /// Shared by the nodes of a deployment to evaluate them all at once
+    pub batched_evaluation: Option<Arc<BatchedEvaluation>>,
+    /// `None` when evaluating without the cache
+    pub eval_cache: Option<Arc<EvalCache>>,
+    /// Shared by the locally built nodes of a rollout batch to build them all
+    /// at once
+    pub batched_build: Option<Arc<BatchedBuild>>,
+}
+
+impl Context<'_> {
+    #[must_use]
+    pub const fn build_location(&self) -> BuildLocation<'_> {
+        self.node.build_location(self.should_apply_locally)
+    }
 }
 
 #[enum_dispatch(ExecuteStep)]
@@ -318,6 +451,9 @@ enum Step {
     Build,
     PushBuildOutput,
     SwitchToConfiguration,
+    HealthCheck,
+    Diff,
+    VerifyKeys,
     CleanUp,
 }
 
@@ -332,14 +468,26 @@ impl Display for Step {
             Self::Build(step) => step.fmt(f),
             Self::PushBuildOutput(step) => step.fmt(f),
             Self::SwitchToConfiguration(step) => step.fmt(f),
+            Self::HealthCheck(step) => step.fmt(f),
+            Self::Diff(step) => step.fmt(f),
+            Self::VerifyKeys(step) => step.fmt(f),
             Self::CleanUp(step) => step.fmt(f),
         }
     }
 }
 
+/// How long a step took to execute, recorded by `GoalExecutor::execute`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepTiming {
+    pub step: String,
+    pub duration_ms: u64,
+    pub success: bool,
+}
+
 pub struct GoalExecutor<'a> {
     steps: Vec<Step>,
     context: Context<'a>,
+    timings: Vec<StepTiming>,
 }
 
 impl<'a> GoalExecutor<'a> {
@@ -360,22 +508,75 @@ impl<'a> GoalExecutor<'a> {
                 Step::Build(super::steps::build::Build),
                 Step::PushBuildOutput(super::steps::push::PushBuildOutput),
                 Step::SwitchToConfiguration(SwitchToConfiguration),
+                Step::Diff(Diff),
                 Step::Keys(Keys {
                     filter: UploadKeyAt::PostActivation,
                 }),
+                // checks services which may need their post-activation keys
+                Step::HealthCheck(HealthCheck),
+                Step::VerifyKeys(VerifyKeys),
                 Step::CleanUp(CleanUp),
             ],
             context,
+            timings: Vec::new(),
         }
     }
 
+    /// Steps executed so far, in order.
+    #[must_use]
+    pub fn timings(&self) -> &[StepTiming] {
+        &self.timings
+    }
+
+    /// The built system's store path, once the node was built.
+    #[must_use]
+    pub fn built_system(&self) -> Option<&str> {
+        self.context.state.build.as_deref()
+    }
+
+    /// What `Goal::Diff` found, for the cli to print.
+    #[must_use]
+    pub const fn diff(&self) -> Option<&SystemDiff> {
+        self.context.state.diff.as_ref()
+    }
+
+    /// What `Goal::VerifyKeys` found, even if keys drifted.
+    #[must_use]
+    pub fn key_drift(&self) -> Option<&[KeyDrift]> {
+        self.context.state.key_drift.as_deref()
+    }
+
     #[instrument(skip_all, name = "eval")]
     async fn evaluate_task(
         tx: oneshot::Sender<Result<Derivation, HiveLibError>>,
         hive_location: Arc<HiveLocation>,
         name: Name,
         modifiers: SubCommandModifiers,
+        batched_evaluation: Option<Arc<BatchedEvaluation>>,
+        eval_cache: Option<Arc<EvalCache>>,
     ) {
+        if let Some(eval_cache) = &eval_cache
+            && let Some(derivation) = eval_cache.get(&name).await
+        {
+            debug!(output = ?derivation, done = true, cached = true);
+
+            let _ = tx.send(Ok(derivation));
+            return;
+        }
+
+        if let Some(batched_evaluation) = batched_evaluation
+            && let Some(derivation) = batched_evaluation.top_level(&name).await
+        {
+            debug!(output = ?derivation, done = true);
+
+            if let Some(eval_cache) = &eval_cache {
+                eval_cache.insert(&name, &derivation).await;
+            }
+
+            let _ = tx.send(Ok(derivation));
+            return;
+        }
+
         let output =
             evaluate_hive_attribute(&hive_location, &EvalGoal::GetTopLevel(&name), modifiers)
                 .await
@@ -385,11 +586,17 @@ impl<'a> GoalExecutor<'a> {
 
         debug!(output = ?output, done = true);
 
+        if let Some(eval_cache) = &eval_cache
+            && let Ok(derivation) = &output
+        {
+            eval_cache.insert(&name, derivation).await;
+        }
+
         let _ = tx.send(output);
     }
 
     #[instrument(skip_all, fields(node = %self.context.name))]
-    pub async fn execute(mut self) -> Result<(), HiveLibError> {
+    pub async fn execute(&mut self) -> Result<(), HiveLibError> {
         let (tx, rx) = oneshot::channel();
         self.context.state.evaluation_rx = Some(rx);
 
@@ -406,13 +613,15 @@ impl<'a> GoalExecutor<'a> {
                 .is_some()
         );
 
-        if !matches!(self.context.goal, Goal::Keys) {
+        if !matches!(self.context.goal, Goal::Keys | Goal::VerifyKeys) {
             tokio::spawn(
                 GoalExecutor::evaluate_task(
                     tx,
                     self.context.hive_location.clone(),
                     self.context.name.clone(),
                     self.context.modifiers,
+                    self.context.batched_evaluation.clone(),
+                    self.context.eval_cache.clone(),
                 )
                 .in_current_span(),
             );
@@ -439,7 +648,24 @@ impl<'a> GoalExecutor<'a> {
                 .lock()
                 .set_node_step(self.context.name, step.to_string());
 
-            if let Err(err) = step.execute(&mut self.context).await.inspect_err(|_| {
+            let started = Instant::now();
+            let result = step.execute(&mut self.context).await;
+            let timing = StepTiming {
+                step: step.to_string(),
+                duration_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+                success: result.is_ok(),
+            };
+
+            event!(
+                Level::DEBUG,
+                step = timing.step,
+                duration_ms = timing.duration_ms,
+                success = timing.success,
+            );
+
+            self.timings.push(timing);
+
+            if let Err(err) = result.inspect_err(|_| {
                 error!("Failed to execute `{step}`");
             }) {
                 // discard error from cleanup
@@ -569,6 +795,29 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn order_verify_keys_only() {
+        let location = location!(get_test_path!());
+        let mut node = Node::default();
+        let name = &Name(function_name!().into());
+        let mut context = Context::create_test_context(location, name, &mut node);
+
+        context.goal = Goal::VerifyKeys;
+
+        let executor = GoalExecutor::new(context);
+        let steps = get_steps(executor);
+
+        assert_eq!(
+            steps,
+            vec![
+                Ping.into(),
+                PushKeyAgent.into(),
+                VerifyKeys.into(),
+                CleanUp.into()
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn order_build_only() {
         let location = location!(get_test_path!());
@@ -616,6 +865,30 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn order_diff_only() {
+        let location = location!(get_test_path!());
+        let mut node = Node::default();
+        let name = &Name(function_name!().into());
+        let mut context = Context::create_test_context(location, name, &mut node);
+
+        context.goal = Goal::Diff;
+
+        let executor = GoalExecutor::new(context);
+        let steps = get_steps(executor);
+
+        assert_eq!(
+            steps,
+            vec![
+                Ping.into(),
+                crate::hive::steps::evaluate::Evaluate.into(),
+                crate::hive::steps::build::Build.into(),
+                Diff.into(),
+                CleanUp.into()
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn order_remote_build() {
         let location = location!(get_test_path!());
@@ -650,6 +923,109 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn order_build_on_node() {
+        let location = location!(get_test_path!());
+        let mut node = Node {
+            build_on: Some(BuildOn::Node(Name("builder".into()))),
+            builder: Some(Target::from_host("builder")),
+            ..Default::default()
+        };
+
+        let name = &Name(function_name!().into());
+        let executor = GoalExecutor::new(Context::create_test_context(location, name, &mut node));
+        let steps = get_steps(executor);
+
+        assert_eq!(
+            steps,
+            vec![
+                Ping.into(),
+                PushKeyAgent.into(),
+                Keys {
+                    filter: UploadKeyAt::PreActivation
+                }
+                .into(),
+                crate::hive::steps::evaluate::Evaluate.into(),
+                crate::hive::steps::push::PushEvaluatedOutput.into(),
+                crate::hive::steps::build::Build.into(),
+                crate::hive::steps::push::PushBuildOutput.into(),
+                SwitchToConfiguration.into(),
+                Keys {
+                    filter: UploadKeyAt::PostActivation
+                }
+                .into(),
+                CleanUp.into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_location() {
+        let builder = Target::from_host("builder");
+        let mut node = Node {
+            build_remotely: true,
+            ..Default::default()
+        };
+
+        assert_eq!(node.build_location(false), BuildLocation::Target);
+        assert_eq!(node.build_location(true), BuildLocation::Local);
+
+        node.build_on = Some(BuildOn::Node(Name("builder".into())));
+        node.builder = Some(builder.clone());
+
+        assert_eq!(node.build_location(false), BuildLocation::Builder(&builder));
+
+        let builders = im::vector!["ssh://builder aarch64-linux".to_string()];
+        node.build_on = Some(BuildOn::Builders(builders.clone()));
+
+        assert_eq!(
+            node.build_location(false),
+            BuildLocation::Builders(&builders)
+        );
+    }
+
+    #[tokio::test]
+    async fn order_health_checks() {
+        let location = location!(get_test_path!());
+        let mut node = Node {
+            health_checks: HealthChecks {
+                units: vec!["nginx.service".into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let name = &Name(function_name!().into());
+        let mut context = Context::create_test_context(location, name, &mut node);
+
+        context.goal = Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch);
+
+        let executor = GoalExecutor::new(context);
+        let steps = get_steps(executor);
+
+        assert_eq!(
+            steps,
+            vec![
+                Ping.into(),
+                PushKeyAgent.into(),
+                Keys {
+                    filter: UploadKeyAt::PreActivation
+                }
+                .into(),
+                crate::hive::steps::evaluate::Evaluate.into(),
+                crate::hive::steps::build::Build.into(),
+                crate::hive::steps::push::PushBuildOutput.into(),
+                SwitchToConfiguration.into(),
+                Keys {
+                    filter: UploadKeyAt::PostActivation
+                }
+                .into(),
+                HealthCheck.into(),
+                CleanUp.into()
+            ]
+        );
+    }
+
     #[test]
     fn target_fails_increments() {
         let mut target = Target::from_host("localhost");
@@ -789,4 +1165,40 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_ssh_opts_user_options() {
+        let subcommand_modifiers = SubCommandModifiers::default();
+        let args = Target::from_host("hello-world")
+            .create_ssh_args(subcommand_modifiers, false, false)
+            .unwrap();
+
+        let target_with_options = Target {
+            ssh_options: vec!["-o".into(), "StrictHostKeyChecking=yes".into()],
+            ..Target::from_host("hello-world")
+        };
+        let (connection, defaults) = args.split_at(4);
+
+        assert_eq!(
+            target_with_options
+                .create_ssh_args(subcommand_modifiers, false, false)
+                .unwrap(),
+            connection
+                .iter()
+                .cloned()
+                .chain(["-o".to_string(), "StrictHostKeyChecking=yes".to_string()])
+                .chain(defaults.iter().cloned())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            target_with_options
+                .create_ssh_opts(subcommand_modifiers, false)
+                .unwrap(),
+            format!(
+                "{} -o StrictHostKeyChecking=yes {}",
+                connection.join(" "),
+                defaults.join(" ")
+            )
+        );
+    }
 }
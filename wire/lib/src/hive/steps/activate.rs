@@ -3,15 +3,23 @@
 
 use std::fmt::Display;
 
+use rand::distr::Alphanumeric;
 use tracing::{error, info, instrument, warn};
 
 use crate::{
     HiveLibError,
-    commands::{CommandArguments, WireCommandChip, run_command},
+    commands::{ChildChip, CommandArguments, Either, WireCommandChip, run_command},
     errors::{ActivationError, NetworkError},
-    hive::node::{Context, ExecuteStep, Goal, SwitchToConfigurationGoal},
+    hive::{
+        node::{Context, ExecuteStep, Goal, SwitchToConfigurationGoal},
+        steps::cleanup::clean_up_control_master,
+    },
 };
 
+/// Symlink to the last system set by wire, so that profiles created by
+/// anything else can be detected.
+const PROFILE_MARKER: &str = "/var/lib/wire/system-profile";
+
 #[derive(Debug, PartialEq)]
 pub struct SwitchToConfiguration;
 
@@ -40,6 +48,242 @@ async fn wait_for_ping(ctx: &Context<'_>) -> Result<(), HiveLibError> {
     result
 }
 
+/// Returns the store path of the system the node is currently running.
+pub(crate) async fn get_current_system(ctx: &Context<'_>) -> Result<String, HiveLibError> {
+    let child = run_command(
+        &CommandArguments::new("readlink -f /run/current-system", ctx.modifiers).on_target(
+            if ctx.should_apply_locally {
+                None
+            } else {
+                Some(&ctx.node.target)
+            },
+        ),
+    )
+    .await?;
+
+    let status = child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    let stdout = match status {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    Ok(stdout.trim().to_string())
+}
+
+async fn spawn_switch_to_configuration(
+    goal: SwitchToConfigurationGoal,
+    built_path: &str,
+    ctx: &Context<'_>,
+) -> Result<ChildChip, HiveLibError> {
+    let command_string = format!(
+        "{built_path}/bin/switch-to-configuration {}",
+        match goal {
+            SwitchToConfigurationGoal::Switch => "switch",
+            SwitchToConfigurationGoal::Boot => "boot",
+            SwitchToConfigurationGoal::Test => "test",
+            SwitchToConfigurationGoal::DryActivate => "dry-activate",
+        }
+    );
+
+    run_command(
+        &CommandArguments::new(command_string, ctx.modifiers)
+            .on_target(if ctx.should_apply_locally {
+                None
+            } else {
+                Some(&ctx.node.target)
+            })
+            .elevated(ctx.node)
+            .log_stdout(),
+    )
+    .await
+}
+
+/// Switches the node back to the system it was running before this
+/// activation.
+pub(crate) async fn rollback(
+    goal: SwitchToConfigurationGoal,
+    ctx: &Context<'_>,
+) -> Result<(), HiveLibError> {
+    let Some(previous) = &ctx.state.previous_system else {
+        warn!(
+            "No previous system was recorded for {name}, not rolling back.",
+            name = ctx.name
+        );
+
+        return Ok(());
+    };
+
+    warn!("Rolling back {name} to {previous}", name = ctx.name);
+
+    let result = async {
+        if matches!(goal, SwitchToConfigurationGoal::Switch) {
+            set_profile(goal, previous, ctx).await?;
+        }
+
+        spawn_switch_to_configuration(goal, previous, ctx)
+            .await?
+            .wait_till_success()
+            .await
+            .map_err(HiveLibError::CommandError)
+    }
+    .await;
+
+    if let Err(err) = result {
+        error!(
+            "Failed to roll back {name}, it may be in a broken state!",
+            name = ctx.name
+        );
+
+        return Err(HiveLibError::ActivationError(
+            ActivationError::RollbackFailed(ctx.name.clone(), previous.clone(), Box::new(err)),
+        ));
+    }
+
+    info!("Rolled back {name} to {previous}", name = ctx.name);
+
+    Ok(())
+}
+
+/// Arms a transient systemd timer on the node which switches back to the
+/// previous system unless it is stopped by `confirm_magic_rollback` in time.
+/// Armed before activation, so a node left unreachable by it is still rolled
+/// back. Returns the name of the timer's unit.
+async fn arm_magic_rollback(
+    goal: SwitchToConfigurationGoal,
+    previous: &str,
+    ctx: &Context<'_>,
+) -> Result<String, HiveLibError> {
+    let unit = format!(
+        "wire-rollback-{}",
+        rand::distr::SampleString::sample_string(&Alphanumeric, &mut rand::rng(), 8).to_lowercase()
+    );
+
+    let rollback_script = if matches!(goal, SwitchToConfigurationGoal::Switch) {
+        format!(
+            "nix-env -p /nix/var/nix/profiles/system --set {previous} && \
+            ln -sfn {previous} {PROFILE_MARKER} && \
+            {previous}/bin/switch-to-configuration switch"
+        )
+    } else {
+        format!("{previous}/bin/switch-to-configuration test")
+    };
+
+    info!(
+        "Arming magic rollback {unit} for {timeout}s",
+        timeout = ctx.node.confirm_timeout
+    );
+
+    let command_string = format!(
+        "systemd-run --unit={unit} --on-active={timeout}s \
+        --timer-property=AccuracySec=1s --description=\"wire magic rollback\" \
+        /bin/sh -c \"{rollback_script}\"",
+        timeout = ctx.node.confirm_timeout
+    );
+
+    let child = run_command(
+        &CommandArguments::new(command_string, ctx.modifiers)
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node),
+    )
+    .await?;
+
+    child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    Ok(unit)
+}
+
+async fn disarm_magic_rollback(unit: &str, ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    let child = run_command(
+        &CommandArguments::new(format!("systemctl stop {unit}.timer"), ctx.modifiers)
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node),
+    )
+    .await?;
+
+    child.wait_till_success().await.map_err(|_| {
+        HiveLibError::ActivationError(ActivationError::MagicRollbackUnconfirmed(ctx.name.clone()))
+    })?;
+
+    Ok(())
+}
+
+/// Reconnects to the node over a fresh SSH session and stops the magic
+/// rollback timer.
+async fn confirm_magic_rollback(unit: &str, ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    // make sure we are not riding on a connection made before activation
+    clean_up_control_master(ctx.node, ctx.modifiers).await?;
+
+    if wait_for_ping(ctx).await.is_err() {
+        error!(
+            "Could not reconnect to {name} to confirm activation, it will roll back within {timeout}s.",
+            name = ctx.name,
+            timeout = ctx.node.confirm_timeout
+        );
+
+        return Err(HiveLibError::ActivationError(
+            ActivationError::MagicRollbackUnconfirmed(ctx.name.clone()),
+        ));
+    }
+
+    disarm_magic_rollback(unit, ctx).await?;
+
+    info!("Confirmed activation of {name}", name = ctx.name);
+
+    Ok(())
+}
+
+/// Refuses to continue if the node's system profile was not set by wire,
+/// unless unknown profiles may be replaced.
+async fn check_profile_is_known(ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    if ctx.node.replace_unknown_profiles || ctx.replace_unknown_profiles {
+        return Ok(());
+    }
+
+    // -m never fails, a missing marker resolves to itself
+    let command_string = format!("readlink -m /nix/var/nix/profiles/system {PROFILE_MARKER}");
+
+    let status = run_command(
+        &CommandArguments::new(command_string, ctx.modifiers).on_target(
+            if ctx.should_apply_locally {
+                None
+            } else {
+                Some(&ctx.node.target)
+            },
+        ),
+    )
+    .await?
+    .wait_till_success()
+    .await
+    .map_err(HiveLibError::CommandError)?;
+
+    let stdout = match status {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    let mut lines = stdout.lines().map(str::trim);
+    let current = lines.next().unwrap_or_default().to_string();
+    let known = lines.next();
+
+    if known == Some(current.as_str()) {
+        return Ok(());
+    }
+
+    error!(
+        "The system profile of {name} ({current}) was not set by wire",
+        name = ctx.name
+    );
+
+    Err(HiveLibError::ActivationError(
+        ActivationError::UnknownProfile(ctx.name.clone(), current),
+    ))
+}
+
 async fn set_profile(
     goal: SwitchToConfigurationGoal,
     built_path: &String,
@@ -61,6 +305,25 @@ async fn set_profile(
     )
     .await?;
 
+    let _ = child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    let child = run_command(
+        &CommandArguments::new(
+            format!("mkdir -p /var/lib/wire && ln -sfn {built_path} {PROFILE_MARKER}"),
+            ctx.modifiers,
+        )
+        .on_target(if ctx.should_apply_locally {
+            None
+        } else {
+            Some(&ctx.node.target)
+        })
+        .elevated(ctx.node),
+    )
+    .await?;
+
     let _ = child
         .wait_till_success()
         .await
@@ -71,6 +334,58 @@ async fn set_profile(
     Ok(())
 }
 
+/// Reboots the node after `switch-to-configuration`, and waits for it to come
+/// back.
+async fn reboot(
+    goal: SwitchToConfigurationGoal,
+    should_roll_back: bool,
+    ctx: &Context<'_>,
+) -> Result<(), HiveLibError> {
+    if ctx.should_apply_locally {
+        error!("Refusing to reboot local machine!");
+
+        return Ok(());
+    }
+
+    warn!("Rebooting {name}!", name = ctx.name);
+
+    let reboot = run_command(
+        &CommandArguments::new("reboot now", ctx.modifiers)
+            .log_stdout()
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node),
+    )
+    .await?;
+
+    // consume result, impossible to know if the machine failed to reboot or we
+    // simply disconnected
+    let _ = reboot
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    info!("Rebooted {name}, waiting to reconnect...", name = ctx.name);
+
+    if wait_for_ping(ctx).await.is_ok() {
+        return Ok(());
+    }
+
+    error!(
+        "Failed to get regain connection to {name} via {host} after reboot.",
+        name = ctx.name,
+        host = ctx.node.target.get_preferred_host()?
+    );
+
+    if should_roll_back {
+        // best effort, the node is most likely still unreachable
+        let _ = rollback(goal, ctx).await;
+    }
+
+    Err(HiveLibError::NetworkError(
+        NetworkError::HostUnreachableAfterReboot(ctx.node.target.get_preferred_host()?.to_string()),
+    ))
+}
+
 impl ExecuteStep for SwitchToConfiguration {
     fn should_execute(&self, ctx: &Context) -> bool {
         matches!(ctx.goal, Goal::SwitchToConfiguration(..))
@@ -78,93 +393,77 @@ impl ExecuteStep for SwitchToConfiguration {
 
     #[instrument(skip_all, name = "activate")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
-        let built_path = ctx.state.build.as_ref().unwrap();
+        let built_path = ctx.state.build.clone().unwrap();
 
-        let Goal::SwitchToConfiguration(goal) = &ctx.goal else {
+        let Goal::SwitchToConfiguration(goal) = ctx.goal else {
             unreachable!("Cannot reach as guarded by should_execute")
         };
 
-        if matches!(
+        let activates_now = matches!(
             goal,
-            // switch profile if switch or boot
-            // https://github.com/NixOS/nixpkgs/blob/a2c92aa34735a04010671e3378e2aa2d109b2a72/pkgs/by-name/ni/nixos-rebuild-ng/src/nixos_rebuild/services.py#L224
-            SwitchToConfigurationGoal::Switch | SwitchToConfigurationGoal::Boot
-        ) {
-            set_profile(*goal, built_path, ctx).await?;
+            SwitchToConfigurationGoal::Switch | SwitchToConfigurationGoal::Test
+        );
+        let should_roll_back = ctx.node.auto_rollback && activates_now;
+
+        // the timer would not survive the reboot, and we refuse to reboot
+        // localhost anyway
+        let magic_rollback =
+            ctx.node.magic_rollback && activates_now && !ctx.should_apply_locally && !ctx.reboot;
+
+        if ctx.node.magic_rollback && activates_now && !magic_rollback {
+            warn!(
+                "Not arming magic rollback for {name} as it is being applied locally or rebooted",
+                name = ctx.name
+            );
         }
 
-        info!("Running switch-to-configuration {goal}");
+        if should_roll_back || magic_rollback {
+            let previous = get_current_system(ctx).await?;
+            info!("Recorded previous system {previous}");
+            ctx.state.previous_system = Some(previous);
+        }
 
-        let command_string = format!(
-            "{built_path}/bin/switch-to-configuration {}",
-            match goal {
-                SwitchToConfigurationGoal::Switch => "switch",
-                SwitchToConfigurationGoal::Boot => "boot",
-                SwitchToConfigurationGoal::Test => "test",
-                SwitchToConfigurationGoal::DryActivate => "dry-activate",
-            }
+        // switch profile if switch or boot
+        // https://github.com/NixOS/nixpkgs/blob/a2c92aa34735a04010671e3378e2aa2d109b2a72/pkgs/by-name/ni/nixos-rebuild-ng/src/nixos_rebuild/services.py#L224
+        let sets_profile = matches!(
+            goal,
+            SwitchToConfigurationGoal::Switch | SwitchToConfigurationGoal::Boot
         );
 
-        let child = run_command(
-            &CommandArguments::new(command_string, ctx.modifiers)
-                .on_target(if ctx.should_apply_locally {
-                    None
-                } else {
-                    Some(&ctx.node.target)
-                })
-                .elevated(ctx.node)
-                .log_stdout(),
-        )
-        .await?;
-
-        let result = child.wait_till_success().await;
-
-        match result {
-            Ok(_) => {
-                if !ctx.reboot {
-                    return Ok(());
-                }
-
-                if ctx.should_apply_locally {
-                    error!("Refusing to reboot local machine!");
+        if sets_profile {
+            check_profile_is_known(ctx).await?;
+        }
 
-                    return Ok(());
-                }
+        // armed before the activation, which may leave the node unreachable
+        let rollback_unit = if magic_rollback {
+            let previous = ctx.state.previous_system.as_ref().unwrap();
+            Some(arm_magic_rollback(goal, previous, ctx).await?)
+        } else {
+            None
+        };
 
-                warn!("Rebooting {name}!", name = ctx.name);
+        if sets_profile {
+            set_profile(goal, &built_path, ctx).await?;
+        }
 
-                let reboot = run_command(
-                    &CommandArguments::new("reboot now", ctx.modifiers)
-                        .log_stdout()
-                        .on_target(Some(&ctx.node.target))
-                        .elevated(ctx.node),
-                )
-                .await?;
+        info!("Running switch-to-configuration {goal}");
 
-                // consume result, impossible to know if the machine failed to reboot or we
-                // simply disconnected
-                let _ = reboot
-                    .wait_till_success()
-                    .await
-                    .map_err(HiveLibError::CommandError)?;
+        let result = spawn_switch_to_configuration(goal, &built_path, ctx)
+            .await?
+            .wait_till_success()
+            .await;
 
-                info!("Rebooted {name}, waiting to reconnect...", name = ctx.name);
+        match result {
+            Ok(_) => {
+                if let Some(unit) = &rollback_unit {
+                    return confirm_magic_rollback(unit, ctx).await;
+                }
 
-                if wait_for_ping(ctx).await.is_ok() {
+                if !ctx.reboot {
                     return Ok(());
                 }
 
-                error!(
-                    "Failed to get regain connection to {name} via {host} after reboot.",
-                    name = ctx.name,
-                    host = ctx.node.target.get_preferred_host()?
-                );
-
-                return Err(HiveLibError::NetworkError(
-                    NetworkError::HostUnreachableAfterReboot(
-                        ctx.node.target.get_preferred_host()?.to_string(),
-                    ),
-                ));
+                reboot(goal, should_roll_back, ctx).await
             }
             Err(error) => {
                 warn!(
@@ -173,18 +472,28 @@ impl ExecuteStep for SwitchToConfiguration {
                 );
 
                 // Bail if the command couldn't of broken the system
-                // and don't try to regain connection to localhost
-                if matches!(goal, SwitchToConfigurationGoal::DryActivate)
-                    || ctx.should_apply_locally
-                {
+                if matches!(goal, SwitchToConfigurationGoal::DryActivate) {
                     return Err(HiveLibError::ActivationError(
-                        ActivationError::SwitchToConfigurationError(*goal, ctx.name.clone(), error),
+                        ActivationError::SwitchToConfigurationError(goal, ctx.name.clone(), error),
                     ));
                 }
 
-                if wait_for_ping(ctx).await.is_ok() {
+                // don't try to regain connection to localhost
+                if ctx.should_apply_locally || wait_for_ping(ctx).await.is_ok() {
+                    // an activation that failed can never be confirmed, roll
+                    // back now instead of waiting for the timer
+                    if let Some(unit) = &rollback_unit
+                        && let Err(err) = disarm_magic_rollback(unit, ctx).await
+                    {
+                        warn!("Failed to stop the magic rollback timer {unit}: {err}");
+                    }
+
+                    if should_roll_back || magic_rollback {
+                        rollback(goal, ctx).await?;
+                    }
+
                     return Err(HiveLibError::ActivationError(
-                        ActivationError::SwitchToConfigurationError(*goal, ctx.name.clone(), error),
+                        ActivationError::SwitchToConfigurationError(goal, ctx.name.clone(), error),
                     ));
                 }
 
@@ -194,6 +503,11 @@ impl ExecuteStep for SwitchToConfiguration {
                     host = ctx.node.target.get_preferred_host()?
                 );
 
+                if should_roll_back || magic_rollback {
+                    // best effort, the node is most likely still unreachable
+                    let _ = rollback(goal, ctx).await;
+                }
+
                 return Err(HiveLibError::NetworkError(
                     NetworkError::HostUnreachableAfterReboot(
                         ctx.node.target.get_preferred_host()?.to_string(),
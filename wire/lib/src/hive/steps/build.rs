@@ -1,16 +1,232 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
-use std::fmt::Display;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+};
 
-use tracing::{info, instrument};
+use itertools::Itertools;
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, instrument, warn};
 
 use crate::{
-    HiveLibError,
-    commands::{CommandArguments, Either, WireCommandChip, run_command_with_env},
-    hive::node::{Context, ExecuteStep, Goal},
+    HiveLibError, SubCommandModifiers,
+    commands::{
+        ChildOutputMode, CommandArguments, Either, WireCommandChip, run_command,
+        run_command_with_env,
+    },
+    errors::HiveInitialisationError,
+    hive::{
+        node::{BuildLocation, Context, Derivation, ExecuteStep, Goal, Name},
+        steps::evaluate::BatchedEvaluation,
+    },
 };
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildResult {
+    drv_path: String,
+    outputs: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ShownDerivation {
+    outputs: HashMap<String, ShownOutput>,
+}
+
+#[derive(Deserialize)]
+struct ShownOutput {
+    path: Option<String>,
+}
+
+/// Builds the top levels of the locally built nodes of a rollout batch in a
+/// single `nix build`, so Nix schedules their derivations together. The derivations are taken
+/// from the `BatchedEvaluation`, the first node to reach its build starts it.
+pub struct BatchedBuild {
+    names: Vec<Name>,
+    batched_evaluation: Arc<BatchedEvaluation>,
+    modifiers: SubCommandModifiers,
+    outputs: OnceCell<HashMap<String, String>>,
+}
+
+impl BatchedBuild {
+    #[must_use]
+    pub fn new(
+        names: Vec<Name>,
+        batched_evaluation: Arc<BatchedEvaluation>,
+        modifiers: SubCommandModifiers,
+    ) -> Self {
+        Self {
+            names,
+            batched_evaluation,
+            modifiers,
+            outputs: OnceCell::new(),
+        }
+    }
+
+    /// Returns `None` if the derivation was not part of the batch, or was not
+    /// built when the batch failed. The caller should then build it on its
+    /// own to surface its error.
+    pub async fn output(&self, derivation: &Derivation) -> Option<String> {
+        self.outputs
+            .get_or_init(|| async {
+                let mut derivations = Vec::with_capacity(self.names.len());
+
+                for name in &self.names {
+                    derivations.extend(self.batched_evaluation.top_level(name).await);
+                }
+
+                match build_all(&derivations, self.modifiers).await {
+                    Ok(outputs) => outputs,
+                    Err(err) => {
+                        let outputs = built_outputs(&derivations, self.modifiers).await;
+
+                        warn!(
+                            "Building {} nodes at once failed, building the {} nodes that were not built on their own: {err}",
+                            derivations.len(),
+                            derivations.len() - outputs.len()
+                        );
+
+                        outputs
+                    }
+                }
+            })
+            .await
+            .get(&derivation.0)
+            .cloned()
+    }
+}
+
+/// Runs a nix command that only queries the store, returning its stdout.
+async fn query(command_string: String, modifiers: SubCommandModifiers) -> Option<String> {
+    let status = run_command(&CommandArguments::new(command_string, modifiers))
+        .await
+        .inspect_err(|err| debug!("failed to query the store: {err}"))
+        .ok()?
+        .wait_till_success()
+        .await
+        .inspect_err(|err| debug!("failed to query the store: {err}"))
+        .ok()?;
+
+    match status {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => Some(stdout),
+    }
+}
+
+/// `nix build --json` prints nothing when any derivation failed, but with
+/// `--keep-going` the others were still built. Returns the `out` path of each
+/// derivation that is in the store, by its path.
+async fn built_outputs(
+    derivations: &[Derivation],
+    modifiers: SubCommandModifiers,
+) -> HashMap<String, String> {
+    let Some(shown) = query(
+        format!(
+            "nix --extra-experimental-features nix-command derivation show {}",
+            derivations.iter().join(" ")
+        ),
+        modifiers,
+    )
+    .await
+    else {
+        return HashMap::new();
+    };
+
+    let outputs = parse_derivation_outputs(&shown)
+        .inspect_err(|err| debug!("failed to parse `nix derivation show`: {err}"))
+        .unwrap_or_default();
+
+    if outputs.is_empty() {
+        return outputs;
+    }
+
+    let Some(invalid) = query(
+        format!(
+            "nix-store --check-validity --print-invalid {}",
+            outputs.values().join(" ")
+        ),
+        modifiers,
+    )
+    .await
+    else {
+        return HashMap::new();
+    };
+
+    let invalid = invalid.lines().collect::<HashSet<_>>();
+
+    outputs
+        .into_iter()
+        .filter(|(_, output)| !invalid.contains(output.as_str()))
+        .collect()
+}
+
+/// Returns the `out` path of each derivation, by its path.
+async fn build_all(
+    derivations: &[Derivation],
+    modifiers: SubCommandModifiers,
+) -> Result<HashMap<String, String>, HiveLibError> {
+    if derivations.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // `--keep-going` so the derivations of other nodes are still built
+    // when one fails, and are already in the store once it is built alone
+    let command_string = format!(
+        "nix --extra-experimental-features nix-command \
+        build --print-build-logs --no-link --keep-going --json {}",
+        derivations.iter().join(" ")
+    );
+
+    let status =
+        run_command(&CommandArguments::new(command_string, modifiers).mode(ChildOutputMode::Nix))
+            .await?
+            .wait_till_success()
+            .await
+            .map_err(|source| HiveLibError::NixBatchBuildError {
+                count: derivations.len(),
+                source,
+            })?;
+
+    let stdout = match status {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    parse_build_results(&stdout).map_err(|err| {
+        HiveLibError::HiveInitialisationError(HiveInitialisationError::ParseEvaluateError(err))
+    })
+}
+
+/// Parses the output of `nix build --json` into the `out` path of each
+/// derivation, by its path.
+fn parse_build_results(stdout: &str) -> Result<HashMap<String, String>, serde_json::Error> {
+    let results: Vec<BuildResult> = serde_json::from_str(stdout)?;
+
+    Ok(results
+        .into_iter()
+        .filter_map(|mut result| {
+            result
+                .outputs
+                .remove("out")
+                .map(|output| (result.drv_path, output))
+        })
+        .collect())
+}
+
+/// Parses the output of `nix derivation show` into the `out` path of each
+/// derivation, by its path. Content addressed outputs have no path before
+/// they are built, and are left out.
+fn parse_derivation_outputs(stdout: &str) -> Result<HashMap<String, String>, serde_json::Error> {
+    let derivations: HashMap<String, ShownDerivation> = serde_json::from_str(stdout)?;
+
+    Ok(derivations
+        .into_iter()
+        .filter_map(|(path, mut derivation)| Some((path, derivation.outputs.remove("out")?.path?)))
+        .collect())
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Build;
 
@@ -22,28 +238,41 @@ impl Display for Build {
 
 impl ExecuteStep for Build {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys | Goal::Push)
+        !matches!(ctx.goal, Goal::Keys | Goal::Push | Goal::VerifyKeys)
     }
 
     #[instrument(skip_all, name = "build")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
         let top_level = ctx.state.evaluation.as_ref().unwrap();
 
+        if let Some(batched_build) = &ctx.batched_build
+            && matches!(ctx.build_location(), BuildLocation::Local)
+            && let Some(output) = batched_build.output(top_level).await
+        {
+            info!("Built output: {output:?}");
+            ctx.state.build = Some(output);
+
+            return Ok(());
+        }
+
+        let (target, builders) = match ctx.build_location() {
+            BuildLocation::Local => (None, String::new()),
+            BuildLocation::Target => (Some(&ctx.node.target), String::new()),
+            BuildLocation::Builder(builder) => (Some(builder), String::new()),
+            BuildLocation::Builders(builders) => (
+                None,
+                format!("--builders '{}' ", builders.iter().join(" ; ")),
+            ),
+        };
+
         let command_string = format!(
             "nix --extra-experimental-features nix-command \
-            build --print-build-logs --no-link --print-out-paths {top_level}"
+            build --print-build-logs --no-link {builders}--print-out-paths {top_level}"
         );
 
         let status = run_command_with_env(
             &CommandArguments::new(command_string, ctx.modifiers)
-                // build remotely if asked for AND we arent applying locally
-                // building remotely but applying locally does not logically
-                // make any sense
-                .on_target(if ctx.node.build_remotely && !ctx.should_apply_locally {
-                    Some(&ctx.node.target)
-                } else {
-                    None
-                })
+                .on_target(target)
                 .mode(crate::commands::ChildOutputMode::Nix)
                 .log_stdout(),
             std::collections::HashMap::new(),
@@ -66,3 +295,53 @@ impl ExecuteStep for Build {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_build_results() {
+        // the shape of `nix build --json`, with a derivation that has no
+        // `out` output
+        let stdout = r#"[{"drvPath":"/nix/store/6kxgfkxg1hqdc4l6lyb5cks0gdzgg8hh-nixos-system-node-a-25.11.drv","outputs":{"out":"/nix/store/0h2mzzjwzl8ywb3j1ijw2nd6jd3dzm5w-nixos-system-node-a-25.11"},"startTime":1760000000,"stopTime":1760000042},{"drvPath":"/nix/store/jd0yj1dpx9w7f0b0lqyz6wbs4v4yqf6n-multi-output.drv","outputs":{"dev":"/nix/store/a0j4r1j6mhqf2yg1cs2rfb2w7v7s3x1a-multi-output-dev"},"startTime":0,"stopTime":0}]"#;
+
+        assert_eq!(
+            parse_build_results(stdout).unwrap(),
+            HashMap::from([(
+                "/nix/store/6kxgfkxg1hqdc4l6lyb5cks0gdzgg8hh-nixos-system-node-a-25.11.drv"
+                    .to_string(),
+                "/nix/store/0h2mzzjwzl8ywb3j1ijw2nd6jd3dzm5w-nixos-system-node-a-25.11".to_string()
+            )])
+        );
+
+        assert_eq!(parse_build_results("[]").unwrap(), HashMap::new());
+        assert!(parse_build_results("error: build failed").is_err());
+    }
+
+    #[test]
+    fn test_parse_derivation_outputs() {
+        // the shape of `nix derivation show`, trimmed to the outputs
+        let stdout = r#"{
+            "/nix/store/6kxgfkxg1hqdc4l6lyb5cks0gdzgg8hh-nixos-system-node-a-25.11.drv": {
+                "outputs": {
+                    "out": { "path": "/nix/store/0h2mzzjwzl8ywb3j1ijw2nd6jd3dzm5w-nixos-system-node-a-25.11" }
+                }
+            },
+            "/nix/store/jd0yj1dpx9w7f0b0lqyz6wbs4v4yqf6n-content-addressed.drv": {
+                "outputs": {
+                    "out": { "hashAlgo": "sha256", "method": "nar" }
+                }
+            }
+        }"#;
+
+        assert_eq!(
+            parse_derivation_outputs(stdout).unwrap(),
+            HashMap::from([(
+                "/nix/store/6kxgfkxg1hqdc4l6lyb5cks0gdzgg8hh-nixos-system-node-a-25.11.drv"
+                    .to_string(),
+                "/nix/store/0h2mzzjwzl8ywb3j1ijw2nd6jd3dzm5w-nixos-system-node-a-25.11".to_string()
+            )])
+        );
+    }
+}
@@ -6,6 +6,8 @@ use std::fmt::Display;
 use tokio::process::Command;
 use tracing::debug;
 
+#[cfg(feature = "native-ssh")]
+use crate::{SshBackend, commands::native::disconnect};
 use crate::{
     SubCommandModifiers,
     errors::HiveLibError,
@@ -25,6 +27,12 @@ pub(crate) async fn clean_up_control_master(
     node: &Node,
     modifiers: SubCommandModifiers,
 ) -> Result<(), HiveLibError> {
+    // the native session is the only connection to wind down
+    #[cfg(feature = "native-ssh")]
+    if matches!(modifiers.ssh_backend, SshBackend::Native) {
+        return disconnect(&node.target).await;
+    }
+
     let output = Command::new("ssh")
         .args(node.target.create_ssh_args(modifiers, true, false)?)
         .args(["-O", "stop", node.target.get_preferred_host()?])
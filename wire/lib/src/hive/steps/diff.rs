@@ -0,0 +1,374 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+
+use itertools::Itertools;
+use owo_colors::{OwoColorize, Stream};
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::{
+    HiveLibError,
+    commands::{CommandArguments, Either, WireCommandChip, run_command},
+    hive::{
+        node::{BuildLocation, Context, ExecuteStep, Goal, Target},
+        steps::activate::get_current_system,
+    },
+};
+
+/// Size changes smaller than this are not worth reporting when the versions
+/// of a package did not change, mirroring `nix store diff-closures`.
+const SIGNIFICANT_SIZE_DELTA: i64 = 8 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Diff;
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Diff the closure")
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PackageDiff {
+    pub name: String,
+    pub old_versions: BTreeSet<String>,
+    pub new_versions: BTreeSet<String>,
+    pub size_delta: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ClosureDiff {
+    pub packages: Vec<PackageDiff>,
+    pub size_delta: i64,
+}
+
+/// The diff between a node's current and built system, left in the step
+/// state by `Diff`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SystemDiff {
+    pub current: String,
+    pub built: String,
+    pub closure: ClosureDiff,
+}
+
+/// Splits a store path into its package name and version, following the same
+/// rule as `builtins.parseDrvName`: the version starts at the first dash
+/// followed by a non-alphabetic character.
+fn parse_name(path: &str) -> (String, String) {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    // strip the store path hash
+    let name = base.split_once('-').map_or(base, |(_, name)| name);
+
+    let split = name
+        .char_indices()
+        .tuple_windows()
+        .find(|((_, dash), (_, next))| *dash == '-' && !next.is_alphabetic())
+        .map(|((index, _), _)| index);
+
+    match split {
+        Some(index) => (name[..index].to_string(), name[index + 1..].to_string()),
+        None => (name.to_string(), String::new()),
+    }
+}
+
+/// Parses the output of `nix path-info --json` into a map of store paths to
+/// their nar size. Accepts both the list output of older nix versions, and
+/// the attribute set output of newer ones.
+pub fn parse_path_info(json: &str) -> Result<HashMap<String, u64>, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+
+    let nar_size = |info: &serde_json::Value| {
+        info.get("narSize")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0)
+    };
+
+    Ok(match value {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| {
+                info.get("path")
+                    .and_then(serde_json::Value::as_str)
+                    .map(|path| (path.to_string(), nar_size(info)))
+            })
+            .collect(),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .map(|(path, info)| (path.clone(), nar_size(info)))
+            .collect(),
+        _ => HashMap::new(),
+    })
+}
+
+fn group_closure(closure: &HashMap<String, u64>) -> BTreeMap<String, (BTreeSet<String>, u64)> {
+    let mut packages: BTreeMap<String, (BTreeSet<String>, u64)> = BTreeMap::new();
+
+    for (path, size) in closure {
+        let (name, version) = parse_name(path);
+        let entry = packages.entry(name).or_default();
+
+        entry.0.insert(version);
+        entry.1 += size;
+    }
+
+    packages
+}
+
+#[allow(clippy::cast_possible_wrap)]
+const fn as_delta(size: u64) -> i64 {
+    size as i64
+}
+
+impl ClosureDiff {
+    #[must_use]
+    pub fn new(old: &HashMap<String, u64>, new: &HashMap<String, u64>) -> Self {
+        let old = group_closure(old);
+        let new = group_closure(new);
+        let empty = (BTreeSet::new(), 0);
+
+        let packages = old
+            .keys()
+            .chain(new.keys())
+            .unique()
+            .sorted()
+            .filter_map(|name| {
+                let (old_versions, old_size) = old.get(name).unwrap_or(&empty);
+                let (new_versions, new_size) = new.get(name).unwrap_or(&empty);
+                let size_delta = as_delta(*new_size) - as_delta(*old_size);
+
+                if old_versions == new_versions && size_delta.abs() < SIGNIFICANT_SIZE_DELTA {
+                    return None;
+                }
+
+                Some(PackageDiff {
+                    name: name.clone(),
+                    old_versions: old_versions.clone(),
+                    new_versions: new_versions.clone(),
+                    size_delta,
+                })
+            })
+            .collect();
+
+        let total = |closure: &BTreeMap<String, (BTreeSet<String>, u64)>| {
+            closure
+                .values()
+                .map(|(_, size)| as_delta(*size))
+                .sum::<i64>()
+        };
+
+        ClosureDiff {
+            packages,
+            size_delta: total(&new) - total(&old),
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    let size = delta.unsigned_abs() as f64;
+
+    if size >= 1024.0 * 1024.0 {
+        format!("{sign}{:.1} MiB", size / (1024.0 * 1024.0))
+    } else {
+        format!("{sign}{:.1} KiB", size / 1024.0)
+    }
+}
+
+fn format_versions(versions: &BTreeSet<String>) -> String {
+    if versions.is_empty() {
+        return "∅".to_string();
+    }
+
+    versions
+        .iter()
+        .map(|version| {
+            if version.is_empty() {
+                "ε"
+            } else {
+                version.as_str()
+            }
+        })
+        .join(", ")
+}
+
+impl Display for ClosureDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.packages.is_empty() {
+            return writeln!(f, "  No changes");
+        }
+
+        for package in &self.packages {
+            write!(f, "  {}: ", package.name.bold())?;
+
+            if package.old_versions != package.new_versions {
+                write!(
+                    f,
+                    "{} → {}",
+                    format_versions(&package.old_versions)
+                        .if_supports_color(Stream::Stdout, |x| x.red()),
+                    format_versions(&package.new_versions)
+                        .if_supports_color(Stream::Stdout, |x| x.green()),
+                )?;
+            }
+
+            if package.size_delta.abs() >= SIGNIFICANT_SIZE_DELTA {
+                if package.old_versions != package.new_versions {
+                    write!(f, ", ")?;
+                }
+
+                write!(
+                    f,
+                    "{}",
+                    format_size_delta(package.size_delta)
+                        .if_supports_color(Stream::Stdout, |x| x.dimmed())
+                )?;
+            }
+
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "  {} {}",
+            "Closure size:".bold(),
+            format_size_delta(self.size_delta)
+        )
+    }
+}
+
+async fn query_closure(
+    ctx: &Context<'_>,
+    path: &str,
+    target: Option<&Target>,
+) -> Result<HashMap<String, u64>, HiveLibError> {
+    let command_string = format!(
+        "nix --extra-experimental-features nix-command path-info --json --recursive {path}"
+    );
+
+    let status = run_command(
+        &CommandArguments::new(command_string, ctx.modifiers)
+            .on_target(target)
+            .mode(crate::commands::ChildOutputMode::Nix),
+    )
+    .await?
+    .wait_till_success()
+    .await
+    .map_err(|source| HiveLibError::NixPathInfoError {
+        name: ctx.name.clone(),
+        path: path.to_string(),
+        source: Box::new(source),
+    })?;
+
+    let stdout = match status {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    parse_path_info(&stdout).map_err(HiveLibError::ParsePathInfo)
+}
+
+impl ExecuteStep for Diff {
+    fn should_execute(&self, ctx: &Context) -> bool {
+        matches!(ctx.goal, Goal::Diff)
+    }
+
+    #[instrument(skip_all, name = "diff")]
+    async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
+        let built_path = ctx.state.build.as_ref().unwrap();
+        let target = if ctx.should_apply_locally {
+            None
+        } else {
+            Some(&ctx.node.target)
+        };
+
+        let current = get_current_system(ctx).await?;
+
+        info!("Diffing {current} against {built_path}");
+
+        let old = query_closure(ctx, &current, target).await?;
+        let new = query_closure(
+            ctx,
+            built_path,
+            // the new system only exists where it was built
+            match ctx.build_location() {
+                BuildLocation::Target => target,
+                BuildLocation::Builder(builder) => Some(builder),
+                BuildLocation::Local | BuildLocation::Builders(..) => None,
+            },
+        )
+        .await?;
+
+        ctx.state.diff = Some(SystemDiff {
+            current,
+            built: built_path.clone(),
+            closure: ClosureDiff::new(&old, &new),
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(
+            parse_name("/nix/store/00000000000000000000000000000000-openssl-3.0.13"),
+            ("openssl".to_string(), "3.0.13".to_string())
+        );
+        assert_eq!(
+            parse_name("/nix/store/00000000000000000000000000000000-nixos-system-node-a-25.05"),
+            ("nixos-system-node-a".to_string(), "25.05".to_string())
+        );
+        assert_eq!(
+            parse_name("/nix/store/00000000000000000000000000000000-etc"),
+            ("etc".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn test_parse_path_info() {
+        let old_format = r#"[{"path":"/nix/store/a-hello-2.12","narSize":1024}]"#;
+        let new_format = r#"{"/nix/store/a-hello-2.12":{"narSize":1024}}"#;
+
+        let expected = HashMap::from([("/nix/store/a-hello-2.12".to_string(), 1024)]);
+
+        assert_eq!(parse_path_info(old_format).unwrap(), expected);
+        assert_eq!(parse_path_info(new_format).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_closure_diff() {
+        let old = HashMap::from([
+            ("/nix/store/a-hello-2.12".to_string(), 100_000),
+            ("/nix/store/b-removed-1.0".to_string(), 20_000),
+            ("/nix/store/c-same-1.0".to_string(), 5_000),
+        ]);
+        let new = HashMap::from([
+            ("/nix/store/d-hello-2.13".to_string(), 110_000),
+            ("/nix/store/e-added-0.1".to_string(), 30_000),
+            ("/nix/store/f-same-1.0".to_string(), 5_100),
+        ]);
+
+        let diff = ClosureDiff::new(&old, &new);
+
+        assert_eq!(
+            diff.packages
+                .iter()
+                .map(|package| package.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["added", "hello", "removed"]
+        );
+        assert_eq!(diff.size_delta, 20_100);
+
+        let hello = &diff.packages[1];
+        assert_eq!(hello.old_versions, BTreeSet::from(["2.12".to_string()]));
+        assert_eq!(hello.new_versions, BTreeSet::from(["2.13".to_string()]));
+        assert_eq!(hello.size_delta, 10_000);
+    }
+}
@@ -1,15 +1,376 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use tracing::instrument;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use itertools::Itertools;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    HiveLibError,
-    hive::node::{Context, ExecuteStep, Goal},
+    EvalGoal, HiveLibError, SubCommandModifiers,
+    commands::{
+        ChildOutputMode, CommandArguments, Either, WireCommandChip,
+        common::evaluate_hive_attribute, run_command,
+    },
+    errors::HiveInitialisationError,
+    hive::{
+        HiveLocation,
+        node::{Context, Derivation, ExecuteStep, Goal, Name},
+    },
 };
 
+#[derive(Deserialize)]
+struct FlakeMetadata {
+    locked: LockedFlake,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LockedFlake {
+    nar_hash: String,
+}
+
+/// The locked source of a flake includes its `flake.lock`, so its hash
+/// changes with any of its inputs.
+async fn flake_source(uri: &str, modifiers: SubCommandModifiers) -> Result<String, HiveLibError> {
+    let command_string = format!(
+        "nix --extra-experimental-features nix-command \
+        --extra-experimental-features flakes \
+        flake metadata --json {uri}"
+    );
+
+    let child =
+        run_command(&CommandArguments::new(command_string, modifiers).mode(ChildOutputMode::Nix))
+            .await?;
+
+    let (Either::Left((_, stdout)) | Either::Right((_, stdout))) = child
+        .wait_till_success()
+        .await
+        .map_err(|source| HiveLibError::NixEvalError {
+            attribute: uri.to_string(),
+            source,
+            help: None,
+        })?;
+
+    let metadata: FlakeMetadata = serde_json::from_str(&stdout).map_err(|err| {
+        HiveLibError::HiveInitialisationError(HiveInitialisationError::ParseEvaluateError(err))
+    })?;
+
+    Ok(metadata.locked.nar_hash)
+}
+
+fn hash_tree(root: &Path, directory: &Path, hasher: &mut Sha256) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+
+    for entry in entries {
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        hasher.update(
+            path.strip_prefix(root)
+                .unwrap_or(&path)
+                .as_os_str()
+                .as_encoded_bytes(),
+        );
+        hasher.update([0]);
+
+        if file_type.is_dir() {
+            hash_tree(root, &path, hasher)?;
+        } else if file_type.is_symlink() {
+            hasher.update(fs::read_link(&path)?.as_os_str().as_encoded_bytes());
+        } else {
+            io::copy(&mut fs::File::open(&path)?, hasher)?;
+        }
+
+        hasher.update([0]);
+    }
+
+    Ok(())
+}
+
+/// The directory of wire's nix runtime. `WIRE_RUNTIME` is set by the wrapper
+/// of the packaged wire, otherwise the runtime of the checkout wire was built
+/// from is used, which only exists on the machine that built it.
+fn runtime_directory() -> Result<PathBuf, HiveLibError> {
+    let directory = env::var_os("WIRE_RUNTIME").map_or_else(
+        || Path::new(env!("CARGO_MANIFEST_DIR")).join("../../runtime"),
+        PathBuf::from,
+    );
+
+    if !directory.is_dir() {
+        return Err(HiveLibError::HiveInitialisationError(
+            HiveInitialisationError::RuntimeNotFound(directory),
+        ));
+    }
+
+    Ok(directory)
+}
+
+/// Hashes wire's nix runtime, which can change without the version of wire
+/// changing.
+fn runtime_source(directory: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+
+    hash_tree(directory, directory, &mut hasher)?;
+
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize()))
+}
+
+/// Remembers the top level each node evaluated to, keyed on the locked source
+/// of the flake and wire's runtime. Each entry is a file in
+/// `$XDG_CACHE_HOME/wire/eval` holding the derivation's path.
+///
+/// Only flakes are cached. A `hive.nix` may read anything, such as files
+/// outside its directory, tarballs or environment variables, and nix does not
+/// report which, so there is no content hash that covers its inputs.
+pub struct EvalCache {
+    directory: PathBuf,
+    runtime: PathBuf,
+    hive_location: Arc<HiveLocation>,
+    modifiers: SubCommandModifiers,
+    source: OnceCell<Option<String>>,
+}
+
+impl EvalCache {
+    /// Returns `None` if the hive is not a flake, or there is no cache
+    /// directory. Fails if wire's runtime cannot be found to key the cache on.
+    pub fn new(
+        hive_location: Arc<HiveLocation>,
+        modifiers: SubCommandModifiers,
+    ) -> Result<Option<Self>, HiveLibError> {
+        if !matches!(*hive_location, HiveLocation::Flake(..)) {
+            debug!("Not using the evaluation cache, the hive is not a flake");
+            return Ok(None);
+        }
+
+        let Some(directory) = env::var_os("XDG_CACHE_HOME")
+            .filter(|directory| !directory.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            directory: directory.join("wire/eval"),
+            runtime: runtime_directory()?,
+            hive_location,
+            modifiers,
+            source: OnceCell::new(),
+        }))
+    }
+
+    /// Hashed once per run. `None` disables the cache.
+    async fn source(&self) -> Option<&str> {
+        self.source
+            .get_or_init(|| async {
+                let HiveLocation::Flake(uri) = &*self.hive_location else {
+                    return None;
+                };
+                let runtime = self.runtime.clone();
+                let source = match flake_source(uri, self.modifiers).await {
+                    Ok(flake) => tokio::task::spawn_blocking(move || runtime_source(&runtime))
+                        .await
+                        .map_err(|err| err.to_string())
+                        .and_then(|runtime| runtime.map_err(|err| err.to_string()))
+                        .map(|runtime| format!("{flake}-{runtime}")),
+                    Err(err) => Err(err.to_string()),
+                };
+
+                source
+                    .inspect_err(|err| {
+                        warn!("Not using the evaluation cache, failed to hash the hive: {err}");
+                    })
+                    .ok()
+            })
+            .await
+            .as_deref()
+    }
+
+    async fn entry(&self, name: &Name) -> Option<PathBuf> {
+        let source = self.source().await?;
+        let location = match &*self.hive_location {
+            HiveLocation::HiveNix(path) => path.to_string_lossy(),
+            HiveLocation::Flake(uri) => uri.into(),
+        };
+        let mut hasher = Sha256::new();
+
+        for part in [env!("CARGO_PKG_VERSION"), &*location, source, &*name.0] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+
+        Some(
+            self.directory
+                .join(BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize())),
+        )
+    }
+
+    pub async fn get(&self, name: &Name) -> Option<Derivation> {
+        let path = fs::read_to_string(self.entry(name).await?).ok()?;
+
+        // the derivation may have been garbage collected since
+        Path::new(&path)
+            .exists()
+            .then_some(Derivation(path))
+            .inspect(|derivation| debug!("Using cached evaluation of {name}: {derivation}"))
+    }
+
+    pub async fn insert(&self, name: &Name, derivation: &Derivation) {
+        let Some(entry) = self.entry(name).await else {
+            return;
+        };
+
+        // written to a temporary file first, so a concurrent run never reads
+        // a partial entry
+        let temporary = entry.with_extension(format!("tmp-{}", std::process::id()));
+        let result = fs::create_dir_all(&self.directory)
+            .and_then(|()| fs::write(&temporary, &derivation.0))
+            .and_then(|()| fs::rename(&temporary, &entry));
+
+        if let Err(err) = result {
+            warn!("Failed to cache the evaluation of {name}: {err}");
+        }
+    }
+}
+
+/// Nodes evaluated together in one `nix eval`. Smaller chunks deliver their
+/// results sooner, and fewer nodes are evaluated again if one is broken.
+const MIN_CHUNK_SIZE: usize = 8;
+
+/// Nodes evaluated in the same `nix eval`. `None` once the evaluation failed.
+struct Chunk {
+    names: Vec<Name>,
+    top_levels: OnceCell<Option<HashMap<String, Derivation>>>,
+}
+
+/// Evaluates the top levels of many nodes in a few `nix eval`s, so the hive
+/// and nixpkgs are not imported for every node. The nodes are split in one
+/// chunk per CPU, of at least `MIN_CHUNK_SIZE` nodes, which are evaluated
+/// concurrently. Shared by the `GoalExecutor` of each node, the first node of
+/// a chunk to ask starts its evaluation.
+pub struct BatchedEvaluation {
+    hive_location: Arc<HiveLocation>,
+    modifiers: SubCommandModifiers,
+    eval_cache: Option<Arc<EvalCache>>,
+    chunks: Vec<Chunk>,
+    /// The index of the chunk each node is evaluated in
+    positions: HashMap<Name, usize>,
+}
+
+impl BatchedEvaluation {
+    #[must_use]
+    pub fn new(
+        names: Vec<Name>,
+        hive_location: Arc<HiveLocation>,
+        modifiers: SubCommandModifiers,
+        eval_cache: Option<Arc<EvalCache>>,
+    ) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, std::num::NonZero::get);
+        let chunk_size = names.len().div_ceil(cpus).max(MIN_CHUNK_SIZE);
+        let chunks = names
+            .into_iter()
+            .chunks(chunk_size)
+            .into_iter()
+            .map(|names| Chunk {
+                names: names.collect(),
+                top_levels: OnceCell::new(),
+            })
+            .collect::<Vec<_>>();
+        let positions = chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(index, chunk)| chunk.names.iter().map(move |name| (name.clone(), index)))
+            .collect();
+
+        Self {
+            hive_location,
+            modifiers,
+            eval_cache,
+            chunks,
+            positions,
+        }
+    }
+
+    async fn evaluate(&self, chunk: &Chunk) -> Option<HashMap<String, Derivation>> {
+        let mut names = Vec::with_capacity(chunk.names.len());
+
+        // cached nodes are not part of the evaluation
+        for name in &chunk.names {
+            if let Some(eval_cache) = &self.eval_cache
+                && eval_cache.get(name).await.is_some()
+            {
+                continue;
+            }
+
+            names.push(name.clone());
+        }
+
+        if names.is_empty() {
+            return Some(HashMap::new());
+        }
+
+        let output = evaluate_hive_attribute(
+            &self.hive_location,
+            &EvalGoal::GetTopLevels(&names),
+            self.modifiers,
+        )
+        .await
+        .and_then(|output| {
+            serde_json::from_str::<HashMap<String, Derivation>>(&output).map_err(|err| {
+                HiveLibError::HiveInitialisationError(HiveInitialisationError::ParseEvaluateError(
+                    err,
+                ))
+            })
+        });
+
+        output
+            .inspect_err(|err| {
+                warn!(
+                    "Evaluating {} nodes at once failed, evaluating each node on its own: {err}",
+                    names.len()
+                );
+            })
+            .ok()
+    }
+
+    /// Returns `None` if the node was not part of the batch, or its chunk
+    /// failed to evaluate. A single broken node fails its whole chunk, so the
+    /// caller should evaluate the node on its own to surface its error.
+    pub async fn top_level(&self, name: &Name) -> Option<Derivation> {
+        if let Some(eval_cache) = &self.eval_cache
+            && let Some(derivation) = eval_cache.get(name).await
+        {
+            return Some(derivation);
+        }
+
+        let chunk = &self.chunks[*self.positions.get(name)?];
+
+        chunk
+            .top_levels
+            .get_or_init(|| self.evaluate(chunk))
+            .await
+            .as_ref()
+            .and_then(|top_levels| top_levels.get(name.0.as_ref()).cloned())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Evaluate;
 
@@ -21,7 +382,7 @@ impl Display for Evaluate {
 
 impl ExecuteStep for Evaluate {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys)
+        !matches!(ctx.goal, Goal::Keys | Goal::VerifyKeys)
     }
 
     #[instrument(skip_all, name = "eval")]
@@ -33,3 +394,52 @@ impl ExecuteStep for Evaluate {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use crate::{get_test_path, location};
+
+    #[tokio::test]
+    #[cfg_attr(feature = "no_web_tests", ignore)]
+    async fn batched_evaluation() {
+        let location = location!(get_test_path!());
+        let batch = BatchedEvaluation::new(
+            vec![Name("node-a".into()), Name("node-b".into())],
+            Arc::new(location),
+            SubCommandModifiers::default(),
+            None,
+        );
+
+        assert!(batch.top_level(&Name("node-a".into())).await.is_some());
+        assert!(batch.top_level(&Name("node-b".into())).await.is_some());
+        assert!(batch.top_level(&Name("node-c".into())).await.is_none());
+    }
+
+    #[test]
+    fn test_hash_tree() {
+        let directory = tempdir::TempDir::new("wire-eval-cache").unwrap();
+        let file = directory.path().join("makeHive.nix");
+        let hash = || {
+            let mut hasher = Sha256::new();
+            hash_tree(directory.path(), directory.path(), &mut hasher).unwrap();
+            hasher.finalize()
+        };
+
+        fs::write(&file, "{ }").unwrap();
+        let source = hash();
+
+        assert_eq!(source, hash());
+
+        fs::write(&file, "{ node-a = { }; }").unwrap();
+
+        assert_ne!(source, hash());
+    }
+
+    #[test]
+    fn test_runtime_source() {
+        assert!(runtime_source(&runtime_directory().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    HiveLibError,
+    commands::{CommandArguments, WireCommandChip, common::shell_quote, run_command},
+    errors::{ActivationError, CommandError},
+    hive::{
+        node::{Context, ExecuteStep, Goal, SwitchToConfigurationGoal},
+        steps::activate::rollback,
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct HealthChecks {
+    pub units: Vec<String>,
+    pub http: Vec<String>,
+    pub commands: Vec<String>,
+    pub attempts: u32,
+    pub interval: u64,
+}
+
+#[cfg(test)]
+impl Default for HealthChecks {
+    fn default() -> Self {
+        HealthChecks {
+            units: Vec::new(),
+            http: Vec::new(),
+            commands: Vec::new(),
+            attempts: 3,
+            interval: 5,
+        }
+    }
+}
+
+impl HealthChecks {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.units.is_empty() && self.http.is_empty() && self.commands.is_empty()
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.units.len() + self.http.len() + self.commands.len()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HealthCheck;
+
+impl Display for HealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Run health checks")
+    }
+}
+
+/// Each check's description, its command, and whether it runs on the node
+/// rather than on the deployer. Every value is quoted, and commands are run
+/// with `sh -c` so they are parsed the same locally and over SSH.
+fn check_commands(checks: &HealthChecks) -> Vec<(String, String, bool)> {
+    let units = checks.units.iter().map(|unit| {
+        (
+            format!("unit {unit}"),
+            format!("systemctl is-active {}", shell_quote(unit)),
+            true,
+        )
+    });
+    let commands = checks.commands.iter().map(|command| {
+        (
+            format!("command `{command}`"),
+            format!("sh -c {}", shell_quote(command)),
+            true,
+        )
+    });
+    let http = checks.http.iter().map(|url| {
+        (
+            format!("http probe {url}"),
+            format!(
+                "curl --fail --silent --show-error --output /dev/null --max-time 10 {}",
+                shell_quote(url)
+            ),
+            false,
+        )
+    });
+
+    units.chain(commands).chain(http).collect()
+}
+
+/// Runs every health check once, returning the first check that failed and why.
+async fn run_checks(ctx: &Context<'_>) -> Result<Option<(String, CommandError)>, HiveLibError> {
+    let target = if ctx.should_apply_locally {
+        None
+    } else {
+        Some(&ctx.node.target)
+    };
+
+    for (check, command_string, on_node) in check_commands(&ctx.node.health_checks) {
+        let child = run_command(
+            &CommandArguments::new(command_string, ctx.modifiers).on_target(if on_node {
+                target
+            } else {
+                None
+            }),
+        )
+        .await?;
+
+        if let Err(err) = child.wait_till_success().await {
+            return Ok(Some((check, err)));
+        }
+
+        info!("Health check passed: {check}");
+    }
+
+    Ok(None)
+}
+
+impl ExecuteStep for HealthCheck {
+    fn should_execute(&self, ctx: &Context) -> bool {
+        matches!(
+            ctx.goal,
+            Goal::SwitchToConfiguration(
+                SwitchToConfigurationGoal::Switch | SwitchToConfigurationGoal::Test
+            )
+        ) && !ctx.node.health_checks.is_empty()
+    }
+
+    #[instrument(skip_all, name = "health")]
+    async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
+        let attempts = ctx.node.health_checks.attempts;
+        let interval = Duration::from_secs(ctx.node.health_checks.interval);
+
+        let mut attempt = 1;
+
+        let (check, source) = loop {
+            match run_checks(ctx).await? {
+                None => {
+                    info!("All health checks passed for {}", ctx.name);
+                    return Ok(());
+                }
+                Some(failure) if attempt >= attempts => break failure,
+                Some((check, _)) => {
+                    warn!("Health check {check} failed (attempt {attempt}/{attempts})");
+                }
+            }
+
+            attempt += 1;
+            tokio::time::sleep(interval).await;
+        };
+
+        error!("Health check {check} failed for {}", ctx.name);
+
+        if ctx.node.auto_rollback {
+            let Goal::SwitchToConfiguration(goal) = ctx.goal else {
+                unreachable!("Cannot reach as guarded by should_execute")
+            };
+
+            rollback(goal, ctx).await?;
+        }
+
+        Err(HiveLibError::ActivationError(
+            ActivationError::HealthCheckFailed {
+                name: ctx.name.clone(),
+                check,
+                source: Box::new(source),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_commands() {
+        let checks = HealthChecks {
+            units: vec!["nginx.service".into()],
+            http: vec!["https://example.com/health?a=1&b=2".into()],
+            commands: vec!["test -e '/run/ready'".into()],
+            attempts: 3,
+            interval: 5,
+        };
+        let commands = check_commands(&checks);
+
+        assert_eq!(
+            commands[0],
+            (
+                "unit nginx.service".to_string(),
+                "systemctl is-active 'nginx.service'".to_string(),
+                true
+            )
+        );
+        assert_eq!(
+            commands[1],
+            (
+                "command `test -e '/run/ready'`".to_string(),
+                r"sh -c 'test -e '\''/run/ready'\'''".to_string(),
+                true
+            )
+        );
+        assert_eq!(
+            commands[2],
+            (
+                "http probe https://example.com/health?a=1&b=2".to_string(),
+                "curl --fail --silent --show-error --output /dev/null --max-time 10 \
+                'https://example.com/health?a=1&b=2'"
+                    .to_string(),
+                false
+            )
+        );
+        assert_eq!(commands.len(), 3);
+    }
+}
@@ -4,12 +4,18 @@
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use futures::future::join_all;
-use itertools::{Itertools, Position};
+use itertools::Itertools;
+use key_agent::keys::{Hello, KeySpec, KeyState, Manifest};
+use key_agent::{
+    HELLO_PREFIX, KEY_CHUNK_SIZE, KEY_STATE_PREFIX, PROTOCOL_VERSION, REMOVED_KEY_PREFIX,
+    capabilities,
+};
 use owo_colors::OwoColorize;
 use prost::Message;
 use prost::bytes::BytesMut;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fmt::Display;
 use std::io::Cursor;
@@ -17,17 +23,20 @@ use std::path::PathBuf;
 use std::pin::Pin;
 use std::process::Stdio;
 use std::str::from_utf8;
+use std::sync::{Arc, LazyLock, nonpoison};
 use tokio::io::AsyncReadExt as _;
 use tokio::process::Command;
+use tokio::sync::OnceCell;
 use tokio::{fs::File, io::AsyncRead};
 use tokio_util::codec::LengthDelimitedCodec;
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument};
 
 use crate::HiveLibError;
 use crate::commands::common::push;
-use crate::commands::{CommandArguments, WireCommandChip, run_command};
-use crate::errors::KeyError;
-use crate::hive::node::{Context, ExecuteStep, Goal, Push, SwitchToConfigurationGoal};
+use crate::commands::{ChildChip, CommandArguments, Either, WireCommandChip, run_command};
+use crate::decrypt::{decrypt_age, decrypt_sops};
+use crate::errors::{CommandError, KeyError};
+use crate::hive::node::{Context, ExecuteStep, Goal, Name, Push, SwitchToConfigurationGoal};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 #[serde(tag = "t", content = "c")]
@@ -35,6 +44,39 @@ pub enum Source {
     String(String),
     Path(PathBuf),
     Command(Vec<String>),
+    Age(AgeSource),
+    Sops(SopsSource),
+    Template(TemplateSource),
+}
+
+/// A file encrypted with age, decrypted by wire before it is uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct AgeSource {
+    pub file: PathBuf,
+    /// Identity files to decrypt with. Defaults to the identities sops uses.
+    #[serde(default)]
+    pub identities: Vec<PathBuf>,
+}
+
+/// A sops file encrypted for age, decrypted by wire before it is uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SopsSource {
+    pub file: PathBuf,
+    /// `.` separated path of the value to upload, otherwise the whole
+    /// decrypted file is uploaded.
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub identities: Vec<PathBuf>,
+}
+
+/// Text with `{{ placeholder }}`s, rendered by wire before it is uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TemplateSource {
+    pub text: String,
+    /// Sources of the `{{ values.<name> }}` placeholders.
+    #[serde(default)]
+    pub values: im::HashMap<String, Source>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
@@ -61,6 +103,27 @@ pub struct Key {
     pub upload_at: UploadKeyAt,
     #[serde(default)]
     pub environment: im::HashMap<String, String>,
+    #[serde(rename = "restartUnits", default)]
+    pub restart_units: im::Vector<String>,
+    #[serde(rename = "reloadUnits", default)]
+    pub reload_units: im::Vector<String>,
+    /// Whether the key agent writes this key with the node's privilege
+    /// escalation command, otherwise as the SSH user.
+    pub elevate: bool,
+    /// Whether the output of the key's source is shared with every node that
+    /// has the same source and environment. Unset shares every source but
+    /// commands, which may generate a different key for each node.
+    #[serde(default)]
+    pub cache: Option<bool>,
+}
+
+impl Key {
+    const fn caches(&self, source: &Source) -> bool {
+        match self.cache {
+            Some(cache) => cache,
+            None => !matches!(source, Source::Command(_)),
+        }
+    }
 }
 
 impl Display for Key {
@@ -72,6 +135,9 @@ impl Display for Key {
                 Source::String(_) => "Literal",
                 Source::Path(_) => "Path",
                 Source::Command(_) => "Command",
+                Source::Age(_) => "Age",
+                Source::Sops(_) => "Sops",
+                Source::Template(_) => "Template",
             }
             .if_supports_color(owo_colors::Stream::Stdout, |x| x.dimmed()),
             [self.dest_dir.clone(), self.name.clone()]
@@ -85,21 +151,50 @@ impl Display for Key {
     }
 }
 
+fn destination(key: &Key) -> String {
+    [key.dest_dir.clone(), key.name.clone()]
+        .iter()
+        .collect::<PathBuf>()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
 fn get_u32_permission(key: &Key) -> Result<u32, KeyError> {
     u32::from_str_radix(&key.permissions, 8).map_err(KeyError::ParseKeyPermissions)
 }
 
-async fn create_reader(key: &'_ Key) -> Result<Pin<Box<dyn AsyncRead + Send + '_>>, KeyError> {
-    match &key.source {
+/// Decryption reads files, and may run `sops`, so it runs off the async
+/// workers.
+async fn decrypt_blocking<S: Send + 'static>(
+    source: S,
+    decrypt: fn(&S) -> Result<Vec<u8>, KeyError>,
+) -> Result<Vec<u8>, KeyError> {
+    tokio::task::spawn_blocking(move || decrypt(&source))
+        .await
+        .map_err(KeyError::DecryptJoin)?
+}
+
+async fn create_reader<'a>(
+    source: &'a Source,
+    environment: &im::HashMap<String, String>,
+) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>, KeyError> {
+    match source {
         Source::Path(path) => Ok(Box::pin(File::open(path).await.map_err(KeyError::File)?)),
         Source::String(string) => Ok(Box::pin(Cursor::new(string))),
+        Source::Age(source) => Ok(Box::pin(Cursor::new(
+            decrypt_blocking(source.clone(), decrypt_age).await?,
+        ))),
+        Source::Sops(source) => Ok(Box::pin(Cursor::new(
+            decrypt_blocking(source.clone(), decrypt_sops).await?,
+        ))),
         Source::Command(args) => {
             let output = Command::new(args.first().ok_or(KeyError::Empty)?)
                 .args(&args[1..])
                 .stdin(Stdio::null())
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
-                .envs(key.environment.clone())
+                .envs(environment.clone())
                 .spawn()
                 .map_err(|err| KeyError::CommandSpawnError {
                     error: err,
@@ -122,11 +217,15 @@ async fn create_reader(key: &'_ Key) -> Result<Pin<Box<dyn AsyncRead + Send + '_
                 from_utf8(&output.stderr).unwrap().to_string(),
             ))
         }
+        Source::Template(_) => Err(KeyError::TemplateNested),
     }
 }
 
-async fn process_key(key: &Key) -> Result<(key_agent::keys::KeySpec, Vec<u8>), KeyError> {
-    let mut reader = create_reader(key).await?;
+async fn read_source(
+    source: &Source,
+    environment: &im::HashMap<String, String>,
+) -> Result<Vec<u8>, KeyError> {
+    let mut reader = create_reader(source, environment).await?;
 
     let mut buf = Vec::new();
 
@@ -135,33 +234,791 @@ async fn process_key(key: &Key) -> Result<(key_agent::keys::KeySpec, Vec<u8>), K
         .await
         .expect("failed to read into buffer");
 
-    let destination: PathBuf = [key.dest_dir.clone(), key.name.clone()].iter().collect();
+    Ok(buf)
+}
+
+type SourceCache = HashMap<(Source, im::HashMap<String, String>), Arc<OnceCell<Vec<u8>>>>;
+
+/// Outputs of the commands and encrypted files read during this run. Each
+/// distinct source is read once, even when nodes read it concurrently.
+static SOURCE_CACHE: LazyLock<nonpoison::Mutex<SourceCache>> =
+    LazyLock::new(|| nonpoison::Mutex::new(HashMap::new()));
+
+/// Reads `source` through `SOURCE_CACHE`. Literals, files and templates are
+/// read as is, as they are cheap to read or differ between nodes.
+async fn read_source_cached(
+    source: &Source,
+    environment: &im::HashMap<String, String>,
+) -> Result<Vec<u8>, KeyError> {
+    if matches!(
+        source,
+        Source::String(_) | Source::Path(_) | Source::Template(_)
+    ) {
+        return read_source(source, environment).await;
+    }
+
+    let cell = SOURCE_CACHE
+        .lock()
+        .entry((source.clone(), environment.clone()))
+        .or_default()
+        .clone();
+
+    if cell.initialized() {
+        debug!("Reusing the output of a key source read earlier in this run");
+    }
+
+    // failures are not cached, the next node to read the source retries it
+    cell.get_or_try_init(|| read_source(source, environment))
+        .await
+        .cloned()
+}
+
+/// Values of the `{{ node.* }}` placeholders, shared by every key of a node.
+fn node_variables(ctx: &Context<'_>) -> Result<HashMap<String, String>, HiveLibError> {
+    Ok(HashMap::from([
+        ("node.name".to_string(), ctx.name.0.to_string()),
+        (
+            "node.host".to_string(),
+            ctx.node.target.get_preferred_host()?.to_string(),
+        ),
+        (
+            "node.tags".to_string(),
+            ctx.node.tags.iter().sorted().join(","),
+        ),
+    ]))
+}
+
+/// Replaces every `{{ placeholder }}` in `text` with its value.
+fn render_template(text: &str, variables: &HashMap<String, String>) -> Result<String, KeyError> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or(KeyError::TemplateUnclosed)?;
+        let placeholder = after[..end].trim();
+
+        rendered.push_str(
+            variables
+                .get(placeholder)
+                .ok_or_else(|| KeyError::TemplatePlaceholder(placeholder.to_string()))?,
+        );
+
+        rest = &after[end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+async fn render_template_source(
+    template: &TemplateSource,
+    key: &Key,
+    node_variables: &HashMap<String, String>,
+) -> Result<Vec<u8>, KeyError> {
+    let mut variables = node_variables.clone();
 
-    debug!("Staging push to {}", destination.clone().display());
+    for (name, source) in &template.values {
+        let value = if key.caches(source) {
+            read_source_cached(source, &key.environment).await?
+        } else {
+            read_source(source, &key.environment).await?
+        };
+        let value =
+            String::from_utf8(value).map_err(|_| KeyError::TemplateValueNotUtf8(name.clone()))?;
+
+        // command output usually ends with a newline
+        variables.insert(
+            format!("values.{name}"),
+            value.trim_end_matches('\n').to_string(),
+        );
+    }
+
+    Ok(render_template(&template.text, &variables)?.into_bytes())
+}
+
+/// Content of a key. Files are streamed from disk when they are uploaded,
+/// every other source is held in memory.
+enum KeyData {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+impl KeyData {
+    async fn reader(&self) -> Result<Pin<Box<dyn AsyncRead + Send + '_>>, KeyError> {
+        match self {
+            Self::Bytes(bytes) => Ok(Box::pin(Cursor::new(bytes))),
+            Self::File(path) => Ok(Box::pin(File::open(path).await.map_err(KeyError::File)?)),
+        }
+    }
+
+    /// Length and digest of the key, read a chunk at a time.
+    async fn digest(&self) -> Result<(u64, Vec<u8>), KeyError> {
+        let mut reader = self.reader().await?;
+        let mut hasher = Sha256::new();
+        let mut length = 0;
+        let mut chunk = Vec::with_capacity(KEY_CHUNK_SIZE);
+
+        loop {
+            read_chunk(&mut reader, &mut chunk)
+                .await
+                .map_err(KeyError::File)?;
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            hasher.update(&chunk);
+            length += chunk.len() as u64;
+        }
+
+        Ok((length, hasher.finalize().to_vec()))
+    }
+}
+
+/// Replaces `chunk` with up to `KEY_CHUNK_SIZE` bytes from `reader`. `chunk`
+/// is empty at the end of `reader`.
+async fn read_chunk(
+    reader: &mut (impl AsyncRead + Unpin),
+    chunk: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    chunk.clear();
+    reader
+        .take(KEY_CHUNK_SIZE as u64)
+        .read_to_end(chunk)
+        .await?;
+
+    Ok(())
+}
+
+async fn process_key(
+    key: &Key,
+    node_variables: &HashMap<String, String>,
+) -> Result<(KeySpec, KeyData), KeyError> {
+    let data = match &key.source {
+        Source::Path(path) => KeyData::File(path.clone()),
+        Source::Template(template) => {
+            KeyData::Bytes(render_template_source(template, key, node_variables).await?)
+        }
+        source if key.caches(source) => {
+            KeyData::Bytes(read_source_cached(source, &key.environment).await?)
+        }
+        source => KeyData::Bytes(read_source(source, &key.environment).await?),
+    };
+
+    let (length, digest) = data.digest().await?;
+    let destination = destination(key);
+
+    debug!("Staging push to {destination}");
 
     Ok((
-        key_agent::keys::KeySpec {
-            length: buf
-                .len()
-                .try_into()
-                .expect("Failed to convert usize buf length to i32"),
+        KeySpec {
+            length,
             user: key.user.clone(),
             group: key.group.clone(),
             permissions: get_u32_permission(key)?,
-            destination: destination.into_os_string().into_string().unwrap(),
-            digest: Sha256::digest(&buf).to_vec(),
-            last: false,
+            destination,
+            digest,
         },
-        buf,
+        data,
     ))
 }
 
+/// How a key differs from the key currently at its destination.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum KeyChange {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+impl Display for KeyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Unchanged => "unchanged",
+        })
+    }
+}
+
+fn key_change(spec: &KeySpec, state: Option<&KeyState>) -> KeyChange {
+    match state {
+        Some(state) if state.exists => {
+            if state.digest == spec.digest
+                && state.user == spec.user
+                && state.group == spec.group
+                && state.permissions == spec.permissions
+            {
+                KeyChange::Unchanged
+            } else {
+                KeyChange::Updated
+            }
+        }
+        _ => KeyChange::Created,
+    }
+}
+
+/// How a key on the node differs from its definition, left in the step
+/// state by `VerifyKeys`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct KeyDrift {
+    pub destination: String,
+    /// Empty if the key is as defined
+    pub drift: Vec<String>,
+}
+
+impl Display for KeyDrift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.drift.is_empty() {
+            return write!(
+                f,
+                "  {} {}",
+                "✓".if_supports_color(owo_colors::Stream::Stdout, |x| x.green()),
+                self.destination
+            );
+        }
+
+        write!(
+            f,
+            "  {} {} {}",
+            "✗".if_supports_color(owo_colors::Stream::Stdout, |x| x.red()),
+            self.destination,
+            format!("({})", self.drift.join(", "))
+                .if_supports_color(owo_colors::Stream::Stdout, |x| x.dimmed())
+        )
+    }
+}
+
+fn key_drift(spec: &KeySpec, state: Option<&KeyState>) -> Vec<String> {
+    let Some(state) = state.filter(|state| state.exists) else {
+        return vec!["missing".to_string()];
+    };
+
+    let mut drift = Vec::new();
+
+    if state.digest != spec.digest {
+        drift.push("content differs".to_string());
+    }
+
+    if state.user != spec.user {
+        drift.push(format!("user is {}, expected {}", state.user, spec.user));
+    }
+
+    if state.group != spec.group {
+        drift.push(format!("group is {}, expected {}", state.group, spec.group));
+    }
+
+    if state.permissions != spec.permissions {
+        drift.push(format!(
+            "mode is {:04o}, expected {:04o}",
+            state.permissions, spec.permissions
+        ));
+    }
+
+    drift
+}
+
+/// Decodes a base64 encoded message the agent printed after `prefix`.
+fn decode_line<M: Message + Default>(line: &str, prefix: &str) -> Option<M> {
+    let encoded = line.trim().strip_prefix(prefix)?;
+
+    M::decode(BASE64_STANDARD.decode(encoded).ok()?.as_slice()).ok()
+}
+
+/// Checks the agent speaks the same protocol version as wire, and supports
+/// every capability wire used.
+fn check_agent_hello(agent: &Hello, wire: &Hello) -> Result<(), KeyError> {
+    if agent.version != wire.version {
+        return Err(KeyError::AgentProtocolVersion {
+            agent: agent.version,
+            wire: wire.version,
+        });
+    }
+
+    let missing = wire
+        .capabilities
+        .iter()
+        .filter(|capability| !agent.capabilities.contains(capability))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        return Err(KeyError::AgentCapabilities(missing.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Keys written as the SSH user must be owned by them, as they cannot chown
+/// the key to anyone else.
+fn check_unelevated_owner(key: &Key, ssh_user: &str) -> Result<(), KeyError> {
+    if key.elevate || key.user == ssh_user {
+        return Ok(());
+    }
+
+    Err(KeyError::UnelevatedOwner {
+        user: key.user.clone(),
+        ssh_user: ssh_user.to_string(),
+    })
+}
+
+/// A key agent running on the node, which greeted wire with a compatible
+/// `Hello`.
+struct AgentSession {
+    name: Name,
+    child: ChildChip,
+    hello: Hello,
+}
+
+impl AgentSession {
+    /// Starts the agent and exchanges hellos. Nothing but wire's hello is sent
+    /// until the agent's hello was read and accepted.
+    async fn start(
+        ctx: &Context<'_>,
+        agent_directory: &str,
+        elevated: bool,
+        capabilities: &[&str],
+    ) -> Result<Self, HiveLibError> {
+        let command_string = format!("{agent_directory}/bin/key_agent");
+
+        let arguments = CommandArguments::new(command_string, ctx.modifiers)
+            .on_target(if ctx.should_apply_locally {
+                None
+            } else {
+                Some(&ctx.node.target)
+            })
+            .keep_stdin_open()
+            .log_stdout();
+
+        let child = run_command(&if elevated {
+            arguments.elevated(ctx.node)
+        } else {
+            arguments
+        })
+        .await?;
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.iter().map(ToString::to_string).collect(),
+        };
+
+        debug!("Writing hello {:?}", hello);
+
+        let mut session = Self {
+            name: ctx.name.clone(),
+            child,
+            hello,
+        };
+        session.send(&session.hello.encode_to_vec()).await?;
+
+        let Some(agent) = session.read::<Hello>(HELLO_PREFIX).await else {
+            return Err(session.exited(KeyError::AgentNoHello).await);
+        };
+
+        check_agent_hello(&agent, &session.hello)
+            .map_err(|err| HiveLibError::KeyAgentHandshake(session.name.clone(), err))?;
+
+        Ok(session)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), HiveLibError> {
+        SimpleLengthDelimWriter::new(async |data| self.child.write_stdin(data).await)
+            .send(BASE64_STANDARD.encode(data).into())
+            .await
+    }
+
+    /// Reads the agent's stdout until the next message printed after
+    /// `prefix`. Returns `None` if stdout closed first.
+    async fn read<M: Message + Default>(&mut self, prefix: &str) -> Option<M> {
+        while let Some(line) = self.child.next_stdout_line().await {
+            if let Some(message) = decode_line(&line, prefix) {
+                return Some(message);
+            }
+        }
+
+        None
+    }
+
+    /// Reports the state of each destination, without writing anything.
+    async fn inspect(
+        mut self,
+        destinations: Vec<String>,
+    ) -> Result<(Self, HashMap<String, KeyState>), HiveLibError> {
+        let inspect = Manifest {
+            destinations,
+            inspect: true,
+            ..Default::default()
+        };
+
+        debug!("Writing manifest {:?}", inspect);
+        self.send(&inspect.encode_to_vec()).await?;
+
+        let mut states = HashMap::new();
+
+        while states.len() < inspect.destinations.len() {
+            let Some(state) = self.read::<KeyState>(KEY_STATE_PREFIX).await else {
+                let missing = inspect
+                    .destinations
+                    .iter()
+                    .filter(|destination| !states.contains_key(*destination))
+                    .join(", ");
+
+                return Err(self.exited(KeyError::AgentNoKeyState(missing)).await);
+            };
+
+            states.insert(state.destination.clone(), state);
+        }
+
+        Ok((self, states))
+    }
+
+    /// Sends the final manifest followed by each key, and waits for the agent
+    /// to write them. Returns the agent's stdout.
+    async fn finish(
+        mut self,
+        manifest: &Manifest,
+        keys: &[(KeySpec, KeyData)],
+    ) -> Result<String, HiveLibError> {
+        debug!("Writing manifest {:?}", manifest);
+        self.send(&manifest.encode_to_vec()).await?;
+
+        for (spec, data) in keys {
+            debug!("Writing spec & data for {:?}", spec);
+
+            self.send(&spec.encode_to_vec()).await?;
+
+            let key_error = |err| HiveLibError::KeyError(spec.destination.clone(), err);
+            let mut reader = data.reader().await.map_err(key_error)?;
+            let mut hasher = Sha256::new();
+            let mut chunk = Vec::with_capacity(KEY_CHUNK_SIZE);
+
+            loop {
+                read_chunk(&mut reader, &mut chunk)
+                    .await
+                    .map_err(|err| key_error(KeyError::File(err)))?;
+
+                if chunk.is_empty() {
+                    break;
+                }
+
+                hasher.update(&chunk);
+                self.send(&chunk).await?;
+            }
+
+            // files are read again for the upload
+            if hasher.finalize().to_vec() != spec.digest {
+                return Err(key_error(KeyError::ChangedDuringUpload));
+            }
+        }
+
+        self.wait().await
+    }
+
+    /// Waits for the agent to exit, returning its stdout.
+    async fn wait(self) -> Result<String, HiveLibError> {
+        let status = self
+            .child
+            .wait_till_success()
+            .await
+            .map_err(HiveLibError::CommandError)?;
+
+        debug!("status: {status:?}");
+
+        let (Either::Left((_, stdout)) | Either::Right((_, stdout))) = status;
+
+        Ok(stdout)
+    }
+
+    /// The agent closed stdout before printing what wire waited for. Returns
+    /// why it exited, or `error` if it exited successfully.
+    async fn exited(self, error: KeyError) -> HiveLibError {
+        let Err(err) = self.child.wait_till_success().await else {
+            return HiveLibError::KeyAgentHandshake(self.name, error);
+        };
+
+        // an agent that rejected wire's hello prints its own to stderr
+        if let CommandError::CommandFailed { logs, .. } = &err
+            && let Some(agent) = logs
+                .lines()
+                .find_map(|line| decode_line::<Hello>(line, HELLO_PREFIX))
+            && let Err(handshake) = check_agent_hello(&agent, &self.hello)
+        {
+            return HiveLibError::KeyAgentHandshake(self.name, handshake);
+        }
+
+        HiveLibError::CommandError(err)
+    }
+}
+
+/// Reads every key of the node matching `filter`, paired with whether the
+/// agent writes it elevated.
+async fn process_keys(
+    ctx: &Context<'_>,
+    filter: &UploadKeyAt,
+) -> Result<Vec<(bool, (KeySpec, KeyData))>, HiveLibError> {
+    let variables = &node_variables(ctx)?;
+    let ssh_user = &ctx.node.target.user;
+
+    let futures = ctx
+        .node
+        .keys
+        .iter()
+        .filter(|key| {
+            *filter == UploadKeyAt::NoFilter
+                || (*filter != UploadKeyAt::NoFilter && key.upload_at != *filter)
+        })
+        .map(|key| async move {
+            check_unelevated_owner(key, ssh_user)
+                .map_err(|err| HiveLibError::KeyError(key.name.clone(), err))?;
+
+            process_key(key, variables)
+                .await
+                .map(|processed| (key.elevate, processed))
+                .map_err(|err| HiveLibError::KeyError(key.name.clone(), err))
+        });
+
+    join_all(futures).await.into_iter().collect()
+}
+
+/// Uploads the keys the agent writes either elevated or as the SSH user, and
+/// removes the stale keys it manages. The agent first reports the state of
+/// each key, so only changed keys are sent in the same session. Returns the
+/// destinations whose content changed.
+async fn upload_keys(
+    ctx: &Context<'_>,
+    agent_directory: &str,
+    elevated: bool,
+    keys: Vec<(KeySpec, KeyData)>,
+    remove_stale: bool,
+) -> Result<HashSet<String>, HiveLibError> {
+    if keys.is_empty() && !remove_stale {
+        return Ok(HashSet::new());
+    }
+
+    let capabilities = [
+        (!keys.is_empty(), capabilities::INSPECT),
+        (!keys.is_empty(), capabilities::CHUNKED_KEYS),
+        (remove_stale, capabilities::REMOVE_STALE),
+    ]
+    .into_iter()
+    .filter_map(|(required, capability)| required.then_some(capability))
+    .collect::<Vec<_>>();
+
+    let session = AgentSession::start(ctx, agent_directory, elevated, &capabilities).await?;
+
+    let (session, states) = if keys.is_empty() {
+        (session, HashMap::new())
+    } else {
+        session
+            .inspect(
+                keys.iter()
+                    .map(|(spec, _)| spec.destination.clone())
+                    .collect(),
+            )
+            .await?
+    };
+
+    let (changed, unchanged): (Vec<_>, Vec<_>) = keys
+        .into_iter()
+        .map(|(spec, data)| {
+            let change = key_change(&spec, states.get(&spec.destination));
+            (spec, data, change)
+        })
+        .partition(|(_, _, change)| *change != KeyChange::Unchanged);
+
+    for (spec, _, change) in &unchanged {
+        debug!("Key {} is {change}", spec.destination);
+    }
+
+    // an empty manifest ends the session without touching anything
+    let manifest = if changed.is_empty() && !remove_stale {
+        debug!("All keys are unchanged, not writing any.");
+        Manifest::default()
+    } else {
+        Manifest {
+            destinations: ctx
+                .node
+                .keys
+                .iter()
+                .filter(|key| key.elevate == elevated)
+                .map(destination)
+                .collect(),
+            remove_stale,
+            keys: changed
+                .len()
+                .try_into()
+                .expect("Failed to convert usize key count to u32"),
+            inspect: false,
+        }
+    };
+    let content_changed = changed
+        .iter()
+        .filter(|(spec, _, _)| {
+            states
+                .get(&spec.destination)
+                .is_none_or(|state| !state.exists || state.digest != spec.digest)
+        })
+        .map(|(spec, _, _)| spec.destination.clone())
+        .collect::<HashSet<_>>();
+    let (statuses, uploads): (Vec<_>, Vec<_>) = changed
+        .into_iter()
+        .map(|(spec, data, change)| ((spec.destination.clone(), change), (spec, data)))
+        .unzip();
+
+    let stdout = session.finish(&manifest, &uploads).await?;
+
+    for (destination, change) in statuses {
+        info!(key = %destination, status = %change, "Key {destination} was {change}");
+    }
+
+    for removed in stdout
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(REMOVED_KEY_PREFIX))
+    {
+        info!("Removed stale key {removed}");
+    }
+
+    Ok(content_changed)
+}
+
+/// Asks the agent for the state of each key's destination, without writing
+/// anything.
+async fn inspect_keys(
+    ctx: &Context<'_>,
+    agent_directory: &str,
+    elevated: bool,
+    keys: &[(KeySpec, KeyData)],
+) -> Result<HashMap<String, KeyState>, HiveLibError> {
+    if keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let session =
+        AgentSession::start(ctx, agent_directory, elevated, &[capabilities::INSPECT]).await?;
+    let (session, states) = session
+        .inspect(
+            keys.iter()
+                .map(|(spec, _)| spec.destination.clone())
+                .collect(),
+        )
+        .await?;
+
+    session.finish(&Manifest::default(), &[]).await?;
+
+    Ok(states)
+}
+
+/// Runs `systemctl {verb}` for all units at once, if there are any. Units of
+/// keys written without elevation are the SSH user's own units.
+async fn systemctl(
+    ctx: &Context<'_>,
+    elevated: bool,
+    verb: &str,
+    units: &[&str],
+) -> Result<(), HiveLibError> {
+    if units.is_empty() {
+        return Ok(());
+    }
+
+    let command_string = format!(
+        "systemctl {}{verb} {}",
+        if elevated { "" } else { "--user " },
+        units.join(" ")
+    );
+
+    info!("Running `{command_string}` as keys changed");
+
+    let arguments = CommandArguments::new(command_string, ctx.modifiers)
+        .on_target(if ctx.should_apply_locally {
+            None
+        } else {
+            Some(&ctx.node.target)
+        })
+        .log_stdout();
+
+    let child = run_command(&if elevated {
+        arguments.elevated(ctx.node)
+    } else {
+        arguments
+    })
+    .await?;
+
+    child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    Ok(())
+}
+
+/// Units to restart or reload because the content of their keys changed, by
+/// whether the key was written elevated. Kept until the system using the keys
+/// is active.
+#[derive(Default, Debug)]
+pub struct PendingUnits {
+    restart: BTreeSet<(bool, String)>,
+    reload: BTreeSet<(bool, String)>,
+}
+
+impl PendingUnits {
+    fn add(&mut self, key: &Key) {
+        self.restart.extend(
+            key.restart_units
+                .iter()
+                .map(|unit| (key.elevate, unit.clone())),
+        );
+        self.reload.extend(
+            key.reload_units
+                .iter()
+                .map(|unit| (key.elevate, unit.clone())),
+        );
+    }
+
+    /// The units to restart and to reload, for keys written either elevated
+    /// or as the SSH user.
+    fn units(&self, elevated: bool) -> (Vec<&str>, Vec<&str>) {
+        let restart = self
+            .restart
+            .iter()
+            .filter(|(elevate, _)| *elevate == elevated)
+            .map(|(_, unit)| unit.as_str())
+            .collect::<Vec<_>>();
+        let reload = self
+            .reload
+            .iter()
+            .filter(|(elevate, _)| *elevate == elevated)
+            .map(|(_, unit)| unit.as_str())
+            // restarting a unit already reloads it
+            .filter(|unit| !restart.contains(unit))
+            .collect();
+
+        (restart, reload)
+    }
+
+    async fn run(&self, ctx: &Context<'_>) -> Result<(), HiveLibError> {
+        for elevated in [true, false] {
+            let (restart, reload) = self.units(elevated);
+
+            systemctl(ctx, elevated, "restart", &restart).await?;
+            systemctl(ctx, elevated, "reload-or-restart", &reload).await?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Keys {
     pub filter: UploadKeyAt,
 }
 #[derive(Debug, PartialEq)]
 pub struct PushKeyAgent;
+#[derive(Debug, PartialEq)]
+pub struct VerifyKeys;
 
 impl Display for Keys {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -169,6 +1026,12 @@ impl Display for Keys {
     }
 }
 
+impl Display for VerifyKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Verify keys")
+    }
+}
+
 impl Display for PushKeyAgent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Push the key agent")
@@ -222,69 +1085,112 @@ impl ExecuteStep for Keys {
     #[instrument(skip_all, name = "keys")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
         let agent_directory = ctx.state.key_agent_directory.as_ref().unwrap();
+        let keys = process_keys(ctx, &self.filter).await?;
+
+        // stale keys are removed once all keys were uploaded, so the previous
+        // system can still use them until it is switched away from
+        let remove_stale = matches!(
+            self.filter,
+            UploadKeyAt::NoFilter | UploadKeyAt::PostActivation
+        );
+
+        if keys.is_empty() && !remove_stale {
+            debug!("No keys to upload @ {:?}", self.filter);
+            return Ok(());
+        }
+
+        let (elevated, unelevated): (Vec<_>, Vec<_>) =
+            keys.into_iter().partition(|(elevate, _)| *elevate);
+        let mut content_changed = HashSet::new();
+
+        // the agent only runs as the SSH user for nodes with such keys, and
+        // only runs elevated if the node has any other keys, so hosts without
+        // privilege escalation can still receive keys
+        if ctx.node.keys.is_empty() || ctx.node.keys.iter().any(|key| key.elevate) {
+            content_changed.extend(
+                upload_keys(
+                    ctx,
+                    agent_directory,
+                    true,
+                    elevated.into_iter().map(|(_, key)| key).collect(),
+                    remove_stale,
+                )
+                .await?,
+            );
+        }
 
-        let futures = ctx
+        if ctx.node.keys.iter().any(|key| !key.elevate) {
+            content_changed.extend(
+                upload_keys(
+                    ctx,
+                    agent_directory,
+                    false,
+                    unelevated.into_iter().map(|(_, key)| key).collect(),
+                    remove_stale,
+                )
+                .await?,
+            );
+        }
+
+        for key in ctx
             .node
             .keys
             .iter()
-            .filter(|key| {
-                self.filter == UploadKeyAt::NoFilter
-                    || (self.filter != UploadKeyAt::NoFilter && key.upload_at != self.filter)
-            })
-            .map(|key| async move {
-                process_key(key)
-                    .await
-                    .map_err(|err| HiveLibError::KeyError(key.name.clone(), err))
-            });
-
-        let mut keys = join_all(futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, HiveLibError>>()?
-            .into_iter()
-            .peekable();
+            .filter(|key| content_changed.contains(&destination(key)))
+        {
+            ctx.state.pending_units.add(key);
+        }
 
-        if keys.peek().is_none() {
-            debug!("Had no keys to push, ending KeyStep early.");
+        // units restarted before activation would still run with the
+        // configuration of the previous system
+        if self.filter == UploadKeyAt::PreActivation {
             return Ok(());
         }
 
-        let command_string = format!("{agent_directory}/bin/key_agent");
-
-        let mut child = run_command(
-            &CommandArguments::new(command_string, ctx.modifiers)
-                .on_target(if ctx.should_apply_locally {
-                    None
-                } else {
-                    Some(&ctx.node.target)
-                })
-                .elevated(ctx.node)
-                .keep_stdin_open()
-                .log_stdout(),
-        )
-        .await?;
+        let pending_units = std::mem::take(&mut ctx.state.pending_units);
+        pending_units.run(ctx).await
+    }
+}
 
-        let mut writer = SimpleLengthDelimWriter::new(async |data| child.write_stdin(data).await);
+impl ExecuteStep for VerifyKeys {
+    fn should_execute(&self, ctx: &Context) -> bool {
+        matches!(ctx.goal, Goal::VerifyKeys)
+    }
 
-        for (position, (mut spec, buf)) in keys.with_position() {
-            if matches!(position, Position::Last | Position::Only) {
-                spec.last = true;
-            }
+    #[instrument(skip_all, name = "verify_keys")]
+    async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
+        let agent_directory = ctx.state.key_agent_directory.as_ref().unwrap();
+        let (elevated, unelevated): (Vec<_>, Vec<_>) = process_keys(ctx, &UploadKeyAt::NoFilter)
+            .await?
+            .into_iter()
+            .partition(|(elevate, _)| *elevate);
+        let elevated = elevated.into_iter().map(|(_, key)| key).collect::<Vec<_>>();
+        let unelevated = unelevated
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
 
-            debug!("Writing spec & buf for {:?}", spec);
+        let mut states = inspect_keys(ctx, agent_directory, true, &elevated).await?;
+        states.extend(inspect_keys(ctx, agent_directory, false, &unelevated).await?);
 
-            writer
-                .send(BASE64_STANDARD.encode(spec.encode_to_vec()).into())
-                .await?;
-            writer.send(BASE64_STANDARD.encode(buf).into()).await?;
-        }
+        let report = elevated
+            .iter()
+            .chain(&unelevated)
+            .map(|(spec, _)| KeyDrift {
+                destination: spec.destination.clone(),
+                drift: key_drift(spec, states.get(&spec.destination)),
+            })
+            .collect::<Vec<_>>();
+        let drifted = report.iter().filter(|key| !key.drift.is_empty()).count();
 
-        let status = child
-            .wait_till_success()
-            .await
-            .map_err(HiveLibError::CommandError)?;
+        ctx.state.key_drift = Some(report);
 
-        debug!("status: {status:?}");
+        if drifted > 0 {
+            return Err(HiveLibError::KeysDrifted {
+                name: ctx.name.clone(),
+                count: drifted,
+            });
+        }
 
         Ok(())
     }
@@ -298,7 +1204,9 @@ impl ExecuteStep for PushKeyAgent {
 
         matches!(
             &ctx.goal,
-            Goal::Keys | Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch)
+            Goal::Keys
+                | Goal::VerifyKeys
+                | Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch)
         )
     }
 
@@ -319,7 +1227,7 @@ impl ExecuteStep for PushKeyAgent {
         };
 
         if !ctx.should_apply_locally {
-            push(ctx, Push::Path(&agent_directory)).await?;
+            push(ctx, &ctx.node.target, Push::Path(&agent_directory)).await?;
         }
 
         ctx.state.key_agent_directory = Some(agent_directory);
@@ -327,3 +1235,307 @@ impl ExecuteStep for PushKeyAgent {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> KeySpec {
+        KeySpec {
+            destination: "/run/keys/key".to_string(),
+            user: "root".to_string(),
+            group: "root".to_string(),
+            permissions: 0o600,
+            length: 3,
+            digest: Sha256::digest(b"key").to_vec(),
+        }
+    }
+
+    fn state(spec: &KeySpec) -> KeyState {
+        KeyState {
+            destination: spec.destination.clone(),
+            exists: true,
+            digest: spec.digest.clone(),
+            user: spec.user.clone(),
+            group: spec.group.clone(),
+            permissions: spec.permissions,
+        }
+    }
+
+    #[test]
+    fn test_key_change() {
+        let spec = spec();
+
+        assert_eq!(key_change(&spec, None), KeyChange::Created);
+        assert_eq!(
+            key_change(
+                &spec,
+                Some(&KeyState {
+                    exists: false,
+                    ..state(&spec)
+                })
+            ),
+            KeyChange::Created
+        );
+        assert_eq!(key_change(&spec, Some(&state(&spec))), KeyChange::Unchanged);
+        assert_eq!(
+            key_change(
+                &spec,
+                Some(&KeyState {
+                    digest: Sha256::digest(b"old").to_vec(),
+                    ..state(&spec)
+                })
+            ),
+            KeyChange::Updated
+        );
+        assert_eq!(
+            key_change(
+                &spec,
+                Some(&KeyState {
+                    permissions: 0o644,
+                    ..state(&spec)
+                })
+            ),
+            KeyChange::Updated
+        );
+    }
+
+    #[test]
+    fn test_check_unelevated_owner() {
+        let key = Key {
+            name: "key".into(),
+            dest_dir: "/home/deploy/".into(),
+            path: "/home/deploy/key".into(),
+            group: "users".into(),
+            user: "root".into(),
+            permissions: "0600".into(),
+            source: Source::String("key".into()),
+            upload_at: UploadKeyAt::PreActivation,
+            environment: im::HashMap::new(),
+            restart_units: im::Vector::new(),
+            reload_units: im::Vector::new(),
+            elevate: true,
+            cache: None,
+        };
+
+        assert!(check_unelevated_owner(&key, "deploy").is_ok());
+
+        let key = Key {
+            elevate: false,
+            ..key
+        };
+
+        assert!(matches!(
+            check_unelevated_owner(&key, "deploy"),
+            Err(KeyError::UnelevatedOwner { .. })
+        ));
+        assert!(
+            check_unelevated_owner(
+                &Key {
+                    user: "deploy".into(),
+                    ..key
+                },
+                "deploy"
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        let variables = HashMap::from([
+            ("node.name".to_string(), "node-a".to_string()),
+            ("values.token".to_string(), "hunter2".to_string()),
+        ]);
+
+        assert_eq!(
+            render_template("host={{ node.name }}\ntoken={{values.token}}\n", &variables).unwrap(),
+            "host=node-a\ntoken=hunter2\n"
+        );
+        assert_eq!(
+            render_template("no placeholders", &variables).unwrap(),
+            "no placeholders"
+        );
+        assert!(matches!(
+            render_template("{{ node.missing }}", &variables),
+            Err(KeyError::TemplatePlaceholder(placeholder)) if placeholder == "node.missing"
+        ));
+        assert!(matches!(
+            render_template("{{ node.name", &variables),
+            Err(KeyError::TemplateUnclosed)
+        ));
+    }
+
+    #[test]
+    fn test_key_drift() {
+        let spec = spec();
+
+        assert_eq!(key_drift(&spec, None), vec!["missing"]);
+        assert!(key_drift(&spec, Some(&state(&spec))).is_empty());
+        assert_eq!(
+            key_drift(
+                &spec,
+                Some(&KeyState {
+                    digest: Sha256::digest(b"old").to_vec(),
+                    user: "alice".to_string(),
+                    permissions: 0o644,
+                    ..state(&spec)
+                })
+            ),
+            vec![
+                "content differs",
+                "user is alice, expected root",
+                "mode is 0644, expected 0600"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_agent_hello() {
+        let wire = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![capabilities::INSPECT.to_string()],
+        };
+        let agent = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: key_agent::CAPABILITIES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        };
+
+        assert!(check_agent_hello(&agent, &wire).is_ok());
+        assert!(matches!(
+            check_agent_hello(
+                &Hello {
+                    version: PROTOCOL_VERSION + 1,
+                    ..agent.clone()
+                },
+                &wire
+            ),
+            Err(KeyError::AgentProtocolVersion { .. })
+        ));
+        assert!(matches!(
+            check_agent_hello(
+                &Hello {
+                    capabilities: Vec::new(),
+                    ..agent.clone()
+                },
+                &wire
+            ),
+            Err(KeyError::AgentCapabilities(missing)) if missing == capabilities::INSPECT
+        ));
+    }
+
+    #[test]
+    fn test_decode_line() {
+        let state = state(&spec());
+        let line = format!(
+            "{KEY_STATE_PREFIX}{}\r",
+            BASE64_STANDARD.encode(state.encode_to_vec())
+        );
+
+        assert_eq!(decode_line(&line, KEY_STATE_PREFIX), Some(state));
+        assert_eq!(decode_line::<KeyState>(&line, HELLO_PREFIX), None);
+        assert_eq!(
+            decode_line::<KeyState>("Writing something", KEY_STATE_PREFIX),
+            None
+        );
+        assert_eq!(
+            decode_line::<KeyState>(&format!("{KEY_STATE_PREFIX}not base64"), KEY_STATE_PREFIX),
+            None
+        );
+    }
+
+    #[test]
+    fn test_key_caches() {
+        let command = Source::Command(vec!["wg".into(), "genkey".into()]);
+        let age = Source::Age(AgeSource {
+            file: "secret.age".into(),
+            identities: Vec::new(),
+        });
+        let mut key = Key {
+            name: "key".into(),
+            dest_dir: "/run/keys/".into(),
+            path: "/run/keys/key".into(),
+            group: "root".into(),
+            user: "root".into(),
+            permissions: "0600".into(),
+            source: command.clone(),
+            upload_at: UploadKeyAt::PreActivation,
+            environment: im::HashMap::new(),
+            restart_units: im::Vector::new(),
+            reload_units: im::Vector::new(),
+            elevate: true,
+            cache: None,
+        };
+
+        // commands may generate a different key for each node
+        assert!(!key.caches(&command));
+        assert!(key.caches(&age));
+
+        key.cache = Some(true);
+        assert!(key.caches(&command));
+
+        key.cache = Some(false);
+        assert!(!key.caches(&age));
+    }
+
+    #[tokio::test]
+    async fn test_read_source_cached() {
+        let directory = tempdir::TempDir::new("wire-key-cache").unwrap();
+        let runs = directory.path().join("runs");
+        let source = Source::Command(vec![
+            "sh".into(),
+            "-c".into(),
+            "echo run >> \"$RUNS\"; echo secret".into(),
+        ]);
+        let environment = im::HashMap::from(vec![("RUNS".to_string(), runs.display().to_string())]);
+
+        let (first, second) = tokio::join!(
+            read_source_cached(&source, &environment),
+            read_source_cached(&source, &environment)
+        );
+
+        assert_eq!(first.unwrap(), b"secret\n");
+        assert_eq!(second.unwrap(), b"secret\n");
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+
+        read_source(&source, &environment).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\nrun\n");
+    }
+
+    #[test]
+    fn test_pending_units() {
+        let key = |elevate, restart_units: &[&str], reload_units: &[&str]| Key {
+            name: "key".into(),
+            dest_dir: "/run/keys/".into(),
+            path: "/run/keys/key".into(),
+            group: "root".into(),
+            user: "root".into(),
+            permissions: "0600".into(),
+            source: Source::String("key".into()),
+            upload_at: UploadKeyAt::PreActivation,
+            environment: im::HashMap::new(),
+            restart_units: restart_units.iter().map(ToString::to_string).collect(),
+            reload_units: reload_units.iter().map(ToString::to_string).collect(),
+            elevate,
+            cache: None,
+        };
+
+        let mut pending = PendingUnits::default();
+        pending.add(&key(
+            true,
+            &["nginx.service"],
+            &["nginx.service", "sshd.service"],
+        ));
+        pending.add(&key(false, &[], &["app.service"]));
+
+        assert_eq!(
+            pending.units(true),
+            (vec!["nginx.service"], vec!["sshd.service"])
+        );
+        assert_eq!(pending.units(false), (vec![], vec!["app.service"]));
+    }
+}
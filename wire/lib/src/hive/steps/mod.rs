@@ -4,7 +4,9 @@
 pub mod activate;
 pub mod build;
 pub mod cleanup;
+pub mod diff;
 pub mod evaluate;
+pub mod health;
 pub mod keys;
 pub mod ping;
 pub mod push;
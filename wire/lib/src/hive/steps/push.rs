@@ -7,8 +7,8 @@ use tracing::instrument;
 
 use crate::{
     HiveLibError,
-    commands::common::push,
-    hive::node::{Context, ExecuteStep, Goal},
+    commands::common::{copy_from_builder, push},
+    hive::node::{BuildLocation, Context, ExecuteStep, Goal},
 };
 
 #[derive(Debug, PartialEq)]
@@ -30,16 +30,24 @@ impl Display for PushBuildOutput {
 
 impl ExecuteStep for PushEvaluatedOutput {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys)
+        !matches!(ctx.goal, Goal::Keys | Goal::VerifyKeys)
             && !ctx.should_apply_locally
-            && (ctx.node.build_remotely | matches!(ctx.goal, Goal::Push))
+            && (matches!(
+                ctx.build_location(),
+                BuildLocation::Target | BuildLocation::Builder(..)
+            ) | matches!(ctx.goal, Goal::Push))
     }
 
     #[instrument(skip_all, name = "push_eval")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
         let top_level = ctx.state.evaluation.as_ref().unwrap();
+        // `Goal::Push` only pushes to the node itself
+        let target = match ctx.build_location() {
+            BuildLocation::Builder(builder) if !matches!(ctx.goal, Goal::Push) => builder,
+            _ => &ctx.node.target,
+        };
 
-        push(ctx, crate::hive::node::Push::Derivation(top_level)).await?;
+        push(ctx, target, crate::hive::node::Push::Derivation(top_level)).await?;
 
         Ok(())
     }
@@ -47,13 +55,16 @@ impl ExecuteStep for PushEvaluatedOutput {
 
 impl ExecuteStep for PushBuildOutput {
     fn should_execute(&self, ctx: &Context) -> bool {
-        if matches!(ctx.goal, Goal::Keys | Goal::Push) {
-            // skip if we are not building
+        if matches!(
+            ctx.goal,
+            Goal::Keys | Goal::Push | Goal::Diff | Goal::VerifyKeys
+        ) {
+            // skip if we are not building, or only need the output locally
             return false;
         }
 
-        if ctx.node.build_remotely {
-            // skip if we are building remotely
+        if matches!(ctx.build_location(), BuildLocation::Target) {
+            // skip if we are building on the node itself
             return false;
         }
 
@@ -69,7 +80,16 @@ impl ExecuteStep for PushBuildOutput {
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
         let built_path = ctx.state.build.as_ref().unwrap();
 
-        push(ctx, crate::hive::node::Push::Path(built_path)).await?;
+        if let BuildLocation::Builder(builder) = ctx.build_location() {
+            return copy_from_builder(ctx, builder, built_path).await;
+        }
+
+        push(
+            ctx,
+            &ctx.node.target,
+            crate::hive::node::Push::Path(built_path),
+        )
+        .await?;
 
         Ok(())
     }
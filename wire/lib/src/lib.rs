@@ -16,6 +16,7 @@ use tokio::sync::{AcquireError, Semaphore, SemaphorePermit};
 use crate::{errors::HiveLibError, hive::node::Name, status::STATUS};
 
 pub mod commands;
+mod decrypt;
 pub mod hive;
 pub mod status;
 
@@ -37,11 +38,23 @@ pub enum StrictHostKeyChecking {
     AcceptNew,
 }
 
+#[derive(Clone, Debug, Copy, Default, PartialEq, Eq)]
+pub enum SshBackend {
+    /// shell out to `ssh`, default
+    #[default]
+    OpenSsh,
+
+    /// in-process ssh client, one multiplexed session per node
+    #[cfg(feature = "native-ssh")]
+    Native,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SubCommandModifiers {
     pub show_trace: bool,
     pub non_interactive: bool,
     pub ssh_accept_host: StrictHostKeyChecking,
+    pub ssh_backend: SshBackend,
 }
 
 impl Default for SubCommandModifiers {
@@ -50,6 +63,7 @@ impl Default for SubCommandModifiers {
             show_trace: false,
             non_interactive: !std::io::stdin().is_terminal(),
             ssh_accept_host: StrictHostKeyChecking::default(),
+            ssh_backend: SshBackend::default(),
         }
     }
 }
@@ -57,6 +71,7 @@ impl Default for SubCommandModifiers {
 pub enum EvalGoal<'a> {
     Inspect,
     GetTopLevel(&'a Name),
+    GetTopLevels(&'a [Name]),
 }
 
 pub static STDIN_CLOBBER_LOCK: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(1));
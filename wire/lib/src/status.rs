@@ -19,6 +19,7 @@ pub enum NodeStatus {
     Running(String),
     Succeeded,
     Failed,
+    Skipped,
 }
 
 pub struct Status {
@@ -60,6 +61,11 @@ impl Status {
         self.statuses.insert(node.0.to_string(), NodeStatus::Failed);
     }
 
+    pub fn mark_node_skipped(&mut self, node: &Name) {
+        self.statuses
+            .insert(node.0.to_string(), NodeStatus::Skipped);
+    }
+
     pub fn mark_node_succeeded(&mut self, node: &Name) {
         self.statuses
             .insert(node.0.to_string(), NodeStatus::Succeeded);
@@ -69,7 +75,12 @@ impl Status {
     fn num_finished(&self) -> usize {
         self.statuses
             .iter()
-            .filter(|(_, status)| matches!(status, NodeStatus::Succeeded | NodeStatus::Failed))
+            .filter(|(_, status)| {
+                matches!(
+                    status,
+                    NodeStatus::Succeeded | NodeStatus::Failed | NodeStatus::Skipped
+                )
+            })
             .count()
     }
 